@@ -0,0 +1,205 @@
+use anyhow::{Error,Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use log::{Level,LevelFilter,Metadata,Record};
+
+// how much gets logged: a default level, and levels for specific modules and their
+// submodules, written like "info,stammer::task_routing=warn"
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    // the most specific module wins
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules.iter().filter(|(module, _)| {
+            target == module || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::"))
+        }).max_by_key(|(module, _)| module.len()).map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, std::cmp::max)
+    }
+}
+
+impl FromStr for LogLevels {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self> {
+        let parse_level = |level: &str| level.trim().parse::<LevelFilter>().map_err(|_| {
+            Error::msg(format!("unknown level {:?}, expected off/error/warn/info/debug/trace", level.trim()))
+        });
+        let mut levels = Self{default: LevelFilter::Info, modules: vec![]};
+        for directive in spec.split(',').filter(|directive| !directive.trim().is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => levels.modules.push((module.trim().to_owned(), parse_level(level)?)),
+                None => levels.default = parse_level(directive)?,
+            }
+        }
+        Ok(levels)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // one json object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::msg(format!("unknown log format {:?}, expected text/json", format))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogOutput {
+    Stderr,
+    // the local syslog daemon, journald included, through /dev/log
+    Syslog,
+    // appended to
+    File(PathBuf),
+}
+
+impl FromStr for LogOutput {
+    type Err = Error;
+    fn from_str(output: &str) -> Result<Self> {
+        match output {
+            "stderr" => Ok(Self::Stderr),
+            "syslog" => Ok(Self::Syslog),
+            "" => Err(Error::msg("empty log output, expected stderr/syslog or a file path")),
+            path => Ok(Self::File(PathBuf::from(path))),
+        }
+    }
+}
+
+// what a line is about beyond its module, like the session stammer's tasks are busy with.
+// text lines end their prefix with " [label]", json lines get the fields
+pub struct LogContext {
+    pub label: String,
+    pub fields: Vec<(&'static str, serde_json::Value)>,
+}
+
+// the levels in use, which the operator may change at any time
+static LEVELS: RwLock<LogLevels> = RwLock::new(LogLevels{default: LevelFilter::Info, modules: Vec::new()});
+
+// installs the logger for the whole process, once. the program name is what syslog
+// lines are tagged with
+pub fn setup_logging(
+    program: &'static str,
+    levels: &LogLevels,
+    format: LogFormat,
+    output: &LogOutput,
+    context: fn() -> Option<LogContext>,
+) -> Result<()> {
+    use fern::{Dispatch,FormatCallback,Output};
+    reload_log_levels(levels);
+    let dispatch = Dispatch::new().filter(|metadata: &Metadata| {
+        metadata.level() <= LEVELS.read().expect("never poisoned").level(metadata.target())
+    }).format(move |out: FormatCallback, message, record: &Record| match format {
+        LogFormat::Text => out.finish(format_args!("{} {:5} {}{} {}",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            record.level(),
+            record.target(),
+            context().map_or(String::new(), |context| format!(" [{}]", context.label)),
+            message,
+        )),
+        LogFormat::Json => out.finish(format_args!("{}", json_line(message, record, context()))),
+    });
+
+    let dispatch = match output {
+        LogOutput::Stderr => dispatch.chain(std::io::stderr()),
+        LogOutput::File(path) => dispatch.chain(fern::log_file(path).map_err(|err| {
+            Error::msg(format!("failed to open log file {}: {}", path.display(), err))
+        })?),
+        LogOutput::Syslog => {
+            use std::os::unix::net::UnixDatagram;
+            let socket = UnixDatagram::unbound()?;
+            socket.connect("/dev/log").map_err(|err| Error::msg(format!("failed to reach syslog: {}", err)))?;
+            let pid = std::process::id();
+            dispatch.chain(Output::call(move |record| {
+                // facility daemon, the syslog daemon timestamps lines itself. nowhere left
+                // to complain to if it fails, so we do not
+                let severity = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let line = format!("<{}>{}[{}]: {}", 3 * 8 + severity, program, pid, record.args());
+                let _ = socket.send(line.as_bytes());
+            }))
+        },
+    };
+    dispatch.apply()?;
+    Ok(())
+}
+
+pub fn reload_log_levels(levels: &LogLevels) {
+    *LEVELS.write().expect("never poisoned") = levels.clone();
+    log::set_max_level(levels.max());
+}
+
+fn json_line(message: &std::fmt::Arguments, record: &Record, context: Option<LogContext>) -> serde_json::Value {
+    let mut line = serde_json::json!({
+        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message.to_string(),
+    });
+    for (key, value) in context.map_or(vec![], |context| context.fields) {
+        line[key] = value;
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels_default_to_info() {
+        let levels = "".parse::<LogLevels>().unwrap();
+        assert_eq!(levels, LogLevels{default: LevelFilter::Info, modules: vec![]});
+        assert_eq!(levels.level("stammer::task_routing"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn most_specific_module_wins() {
+        let levels = " warn, stammer=debug ,stammer::task_routing=error".parse::<LogLevels>().unwrap();
+        assert_eq!(levels.level("hyper::client"), LevelFilter::Warn);
+        assert_eq!(levels.level("stammer"), LevelFilter::Debug);
+        assert_eq!(levels.level("stammer::task_control"), LevelFilter::Debug);
+        assert_eq!(levels.level("stammer::task_routing"), LevelFilter::Error);
+        assert_eq!(levels.level("stammer::task_routing::inner"), LevelFilter::Error);
+        // a module name is not a prefix of any other
+        assert_eq!(levels.level("stammerx"), LevelFilter::Warn);
+        assert_eq!(levels.max(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn bad_levels_are_refused() {
+        assert!("loud".parse::<LogLevels>().is_err());
+        assert!("info,stammer=loud".parse::<LogLevels>().is_err());
+    }
+
+    #[test]
+    fn formats_and_outputs() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        assert_eq!("stderr".parse::<LogOutput>().unwrap(), LogOutput::Stderr);
+        assert_eq!("syslog".parse::<LogOutput>().unwrap(), LogOutput::Syslog);
+        assert_eq!("/var/log/stammer.log".parse::<LogOutput>().unwrap(), LogOutput::File("/var/log/stammer.log".into()));
+        assert!("".parse::<LogOutput>().is_err());
+    }
+}
@@ -8,6 +8,8 @@ use protobuf::error::ProtobufError;
 use protobuf::Message;
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
 use std::io;
 use std::io::Cursor;
 use std::marker::PhantomData;
@@ -43,20 +45,72 @@ pub struct RawControlPacket {
     pub bytes: Bytes,
 }
 
+/// The default maximum length of a control packet's payload, in bytes.
+///
+/// This is the limit the reference implementation enforces.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 0x7f_ffff;
+
+/// Error returned when decoding a control packet whose payload exceeds the codec's maximum
+/// frame length.
+///
+/// The payload is never buffered: this error is returned as soon as the packet header is read.
+/// It is wrapped in an [io::Error] of kind [io::ErrorKind::InvalidData], from which it can be
+/// recovered with [io::Error::get_ref] and [Error::downcast_ref].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameTooLarge {
+    /// ID of the offending packet.
+    pub id: u16,
+    /// Payload length announced by the packet header.
+    pub len: usize,
+    /// Maximum payload length of the codec which refused the packet.
+    pub max_len: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "control packet {} of {} bytes exceeds the {} bytes limit",
+            self.id, self.len, self.max_len
+        )
+    }
+}
+
+impl Error for FrameTooLarge {}
+
+impl From<FrameTooLarge> for io::Error {
+    fn from(err: FrameTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
 /// A `Codec` implementation that parses a stream of data into [RawControlPacket]s.
 #[derive(Debug)]
-pub struct RawControlCodec;
+pub struct RawControlCodec {
+    max_frame_len: usize,
+}
 
 impl RawControlCodec {
     /// Creates a new RawControlCodec.
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Creates a new RawControlCodec which refuses to decode packets whose payload is longer
+    /// than `max_frame_len` bytes, see [FrameTooLarge].
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        RawControlCodec { max_frame_len }
+    }
+
+    /// Returns the maximum payload length of the packets this codec decodes.
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
 }
 
 impl Default for RawControlCodec {
     fn default() -> Self {
-        RawControlCodec
+        RawControlCodec::with_max_frame_len(DEFAULT_MAX_FRAME_LEN)
     }
 }
 
@@ -70,8 +124,13 @@ impl Decoder for RawControlCodec {
             let mut buf = Cursor::new(buf);
             let id = buf.get_u16();
             let len = buf.get_u32() as usize;
-            if len > 0x7f_ffff {
-                Err(io::Error::new(io::ErrorKind::Other, "packet too long"))
+            if len > self.max_frame_len {
+                Err(FrameTooLarge {
+                    id,
+                    len,
+                    max_len: self.max_frame_len,
+                }
+                .into())
             } else if buf_len >= 6 + len {
                 let mut bytes = buf.into_inner().split_to(6 + len);
                 bytes.advance(6);
@@ -122,6 +181,21 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> ControlCodec<EncodeDs
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Creates a new control codec which refuses to decode packets whose payload is longer
+    /// than `max_frame_len` bytes, see [FrameTooLarge].
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        ControlCodec {
+            inner: RawControlCodec::with_max_frame_len(max_frame_len),
+            _encode_dst: PhantomData,
+            _decode_dst: PhantomData,
+        }
+    }
+
+    /// Returns the maximum payload length of the packets this codec decodes.
+    pub fn max_frame_len(&self) -> usize {
+        self.inner.max_frame_len()
+    }
 }
 
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> Default
@@ -380,3 +454,43 @@ define_packets![
     #[cfg(feature = "webrtc-extensions")]
     TalkingState(msgs::TalkingState),
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(id: u16, len: u32, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u16(id);
+        buf.put_u32(len);
+        buf.put_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn oversized_frame_is_refused_before_payload_arrives() {
+        let mut codec = RawControlCodec::with_max_frame_len(16);
+        let mut buf = frame(msgs::id::TextMessage, 17, &[]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.get_ref().unwrap().downcast_ref::<FrameTooLarge>().unwrap();
+        assert_eq!(
+            err,
+            &FrameTooLarge {
+                id: msgs::id::TextMessage,
+                len: 17,
+                max_len: 16,
+            }
+        );
+    }
+
+    #[test]
+    fn frame_at_max_len_is_decoded() {
+        let mut codec = RawControlCodec::with_max_frame_len(16);
+        let mut buf = frame(msgs::id::TextMessage, 16, &[0; 16]);
+        let packet = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(packet.id, msgs::id::TextMessage);
+        assert_eq!(packet.bytes.len(), 16);
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,565 @@
+use anyhow::{Error,Result};
+use serde::{Deserialize,Serialize};
+use std::collections::{BTreeSet,HashMap};
+use mumble_protocol::control::msgs;
+use super::routing_table::{RoutingTable,ROOT_ROOM_ID};
+use super::registry::SUPERUSER_ID;
+use super::storage::AclRecord;
+
+type SessionID = u32;
+type RoomID = u32;
+
+// the permission bits, which are part of the protocol. see:
+// https://github.com/mumble-voip/mumble/blob/master/src/ACL.h
+pub type Permissions = u32;
+pub const WRITE: Permissions = 0x1;
+pub const TRAVERSE: Permissions = 0x2;
+pub const ENTER: Permissions = 0x4;
+pub const SPEAK: Permissions = 0x8;
+pub const MUTE_DEAFEN: Permissions = 0x10;
+pub const MOVE: Permissions = 0x20;
+pub const MAKE_CHANNEL: Permissions = 0x40;
+pub const LINK_CHANNEL: Permissions = 0x80;
+pub const WHISPER: Permissions = 0x100;
+pub const TEXT_MESSAGE: Permissions = 0x200;
+pub const MAKE_TEMP_CHANNEL: Permissions = 0x400;
+pub const LISTEN: Permissions = 0x800;
+// those only mean something in the root room
+pub const KICK: Permissions = 0x10000;
+pub const BAN: Permissions = 0x20000;
+pub const REGISTER: Permissions = 0x40000;
+pub const SELF_REGISTER: Permissions = 0x80000;
+pub const RESET_USER_CONTENT: Permissions = 0x100000;
+pub const ALL: Permissions = 0xfff | 0x1f0000;
+
+// what everybody gets before any acl entry is applied
+const DEFAULT: Permissions = TRAVERSE | ENTER | SPEAK | WHISPER | TEXT_MESSAGE | LISTEN;
+// what write implies, it makes admins of whoever holds it
+const WRITE_IMPLIES: Permissions = TRAVERSE | ENTER | MUTE_DEAFEN | MOVE | MAKE_CHANNEL
+    | LINK_CHANNEL | TEXT_MESSAGE | MAKE_TEMP_CHANNEL | LISTEN;
+const WRITE_IMPLIES_AT_ROOT: Permissions = KICK | BAN | REGISTER | SELF_REGISTER | RESET_USER_CONTENT;
+
+// the access control lists of all rooms, along with their groups, which work like murmur's:
+//
+//  - the acl entries of a room apply to it (apply_here) and/or its sub-rooms (apply_subs),
+//    they grant and deny permissions to a registered user or to a group
+//  - entries are applied from the root down to the room, unless a room does not inherit
+//    the entries of its parents, in which case we start from that room instead
+//  - groups are either built-in (all, auth, in, ...) or defined per room, in which case
+//    sub-rooms may inherit their members and add/remove some of their own
+//
+// rooms we hold nothing for inherit everything and have no entries nor groups
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rooms: HashMap<RoomID, RoomAcl>,
+}
+
+#[derive(Clone, Debug)]
+struct RoomAcl {
+    inherit: bool,
+    entries: Vec<AclEntry>,
+    groups: HashMap<String, Group>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclEntry {
+    pub apply_here: bool,
+    pub apply_subs: bool,
+    // entries apply either to a registered user, or to a group
+    pub user_id: Option<u32>,
+    pub group: Option<String>,
+    pub grant: Permissions,
+    pub deny: Permissions,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    // whether members of the same group up the tree are members here too
+    pub inherit: bool,
+    // whether sub-rooms may inherit the members of this group
+    pub inheritable: bool,
+    // registered users, by id
+    pub add: BTreeSet<u32>,
+    pub remove: BTreeSet<u32>,
+}
+
+impl Default for RoomAcl {
+    fn default() -> Self {
+        Self{inherit: true, entries: vec![], groups: HashMap::new()}
+    }
+}
+
+// the subject of a permission evaluation
+struct Subject<'a> {
+    room_id: RoomID,
+    user_id: Option<u32>,
+    cert_hash: Option<&'a str>,
+    tokens: &'a [String],
+}
+
+impl Acl {
+    // records are checked at startup. a root without record gets murmur's defaults:
+    // admins do everything, registered users make rooms, anybody may register
+    pub fn restore(records: &[AclRecord]) -> Self {
+        let mut rooms: HashMap<RoomID, RoomAcl> = records.iter().map(|record| (record.room_id, RoomAcl{
+            inherit: record.inherit,
+            entries: record.entries.clone(),
+            groups: record.groups.iter().map(|group| (group.name.clone(), group.clone())).collect(),
+        })).collect();
+
+        rooms.entry(ROOT_ROOM_ID).or_insert_with(|| {
+            let entry = |group: &str, grant| AclEntry{
+                apply_here: true,
+                apply_subs: true,
+                user_id: None,
+                group: Some(group.to_owned()),
+                grant,
+                deny: 0,
+            };
+            let admin = Group{
+                name: "admin".to_owned(),
+                inherit: true,
+                inheritable: true,
+                add: BTreeSet::new(),
+                remove: BTreeSet::new(),
+            };
+            RoomAcl{
+                inherit: true,
+                entries: vec![entry("admin", WRITE), entry("auth", MAKE_CHANNEL), entry("all", SELF_REGISTER)],
+                groups: vec![(admin.name.clone(), admin)].into_iter().collect(),
+            }
+        });
+        Self{rooms}
+    }
+
+    pub fn records(&self) -> Vec<AclRecord> {
+        let mut records: Vec<AclRecord> = self.rooms.iter().map(|(room_id, room_acl)| {
+            let mut groups: Vec<Group> = room_acl.groups.values().cloned().collect();
+            groups.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            AclRecord{room_id: *room_id, inherit: room_acl.inherit, entries: room_acl.entries.clone(), groups}
+        }).collect();
+        records.sort_unstable_by_key(|record| record.room_id);
+        records
+    }
+
+    pub fn remove_rooms(&mut self, room_ids: &[RoomID]) {
+        for room_id in room_ids {
+            self.rooms.remove(room_id);
+        }
+    }
+
+    pub fn has_permission(&self, rtbl: &RoutingTable, session_id: SessionID, room_id: RoomID, perm: Permissions) -> bool {
+        self.permissions(rtbl, session_id, room_id) & perm == perm
+    }
+
+    // the permissions of a session in a room
+    pub fn permissions(&self, rtbl: &RoutingTable, session_id: SessionID, room_id: RoomID) -> Permissions {
+        let subject = match rtbl.room_id(session_id) {
+            Err(_) => return 0,
+            Ok(subject_room_id) => Subject{
+                room_id: subject_room_id,
+                user_id: rtbl.user_id(session_id),
+                cert_hash: rtbl.cert_hash(session_id),
+                tokens: rtbl.tokens(session_id),
+            },
+        };
+
+        // like murmur, the superuser may do anything but talk
+        if subject.user_id == Some(SUPERUSER_ID) {
+            return ALL & !(SPEAK | WHISPER)
+        }
+
+        let mut granted = DEFAULT;
+        let mut traverse = true;
+        let mut write = false;
+        for acl_room_id in self.chain(rtbl, room_id) {
+            let room_acl = match self.rooms.get(&acl_room_id) {
+                Some(room_acl) => room_acl,
+                None => continue,
+            };
+            for entry in &room_acl.entries {
+                let matches_user = entry.user_id.is_some() && entry.user_id == subject.user_id;
+                let matches_group = match &entry.group {
+                    Some(group) => self.in_group(rtbl, room_id, acl_room_id, group, &subject),
+                    None => false,
+                };
+                if !matches_user && !matches_group {
+                    continue
+                }
+
+                // traversal and write are decided all the way down, wherever the entry applies
+                if entry.grant & TRAVERSE != 0 { traverse = true; }
+                if entry.deny & TRAVERSE != 0 { traverse = false; }
+                if entry.grant & WRITE != 0 { write = true; }
+                if entry.deny & WRITE != 0 { write = false; }
+                if (acl_room_id == room_id && entry.apply_here) || (acl_room_id != room_id && entry.apply_subs) {
+                    granted |= entry.grant;
+                    granted &= !entry.deny;
+                }
+            }
+
+            // rooms nobody can get through hide everything below them
+            if !traverse && !write {
+                return 0
+            }
+        }
+
+        if granted & WRITE != 0 {
+            granted |= WRITE_IMPLIES;
+            if room_id == ROOT_ROOM_ID {
+                granted |= WRITE_IMPLIES_AT_ROOT;
+            }
+        }
+        granted
+    }
+
+    // the rooms whose entries apply to a room, root-most first
+    fn chain(&self, rtbl: &RoutingTable, room_id: RoomID) -> Vec<RoomID> {
+        let mut chain = vec![];
+        let mut current = Some(room_id);
+        while let Some(room_id) = current {
+            chain.push(room_id);
+            if matches!(self.rooms.get(&room_id), Some(room_acl) if !room_acl.inherit) {
+                break
+            }
+            current = rtbl.room_parent(room_id);
+        }
+        chain.reverse();
+        chain
+    }
+
+    // group names may be prefixed with:
+    //
+    //  - '!' which inverts the membership
+    //  - '~' which evaluates the group in the room of the acl entry, rather than the one
+    //    permissions are computed for
+    //  - '$' which makes a group of the client certificate with that hash
+    //  - '#' which makes a group of the sessions holding that access token
+    //
+    // the built-in groups are none, all, auth (registered users), strong (users with a
+    // certificate), in/out (users in the room or not) and sub,a,b,c (users in sub-rooms)
+    fn in_group(&self, rtbl: &RoutingTable, room_id: RoomID, acl_room_id: RoomID, group: &str, subject: &Subject) -> bool {
+        let mut name = group;
+        let mut invert = false;
+        let mut context = room_id;
+        let mut hash = false;
+        let mut token = false;
+        while let Some(prefix) = name.chars().next() {
+            match prefix {
+                '!' => invert = true,
+                '~' => context = acl_room_id,
+                '$' => hash = true,
+                '#' => token = true,
+                _ => break,
+            }
+            name = &name[1..];
+        }
+
+        let member = if hash {
+            subject.cert_hash == Some(name)
+        } else if token {
+            // like murmur, tokens are passphrases typed by people, case does not matter
+            subject.tokens.iter().any(|token| token.to_lowercase() == name.to_lowercase())
+        } else {
+            match name {
+                "none" => false,
+                "all" => true,
+                "auth" => subject.user_id.is_some(),
+                "strong" => subject.cert_hash.is_some(),
+                "in" => subject.room_id == context,
+                "out" => subject.room_id != context,
+                name if name == "sub" || name.starts_with("sub,") => in_sub(rtbl, context, name, subject),
+                name => match subject.user_id {
+                    Some(user_id) => self.members(rtbl, context, name).contains(&user_id),
+                    None => false,
+                },
+            }
+        };
+        member != invert
+    }
+
+    // the members of a custom group as seen from a room. we walk up the tree for as long
+    // as groups of that name inherit, then apply their additions/removals top-down
+    fn members(&self, rtbl: &RoutingTable, room_id: RoomID, name: &str) -> BTreeSet<u32> {
+        let mut groups = vec![];
+        let mut current = Some(room_id);
+        while let Some(current_id) = current {
+            if let Some(group) = self.rooms.get(&current_id).and_then(|room_acl| room_acl.groups.get(name)) {
+                if current_id != room_id && !group.inheritable {
+                    break
+                }
+                groups.push(group);
+                if !group.inherit {
+                    break
+                }
+            }
+            current = rtbl.room_parent(current_id);
+        }
+
+        let mut members = BTreeSet::new();
+        for group in groups.into_iter().rev() {
+            members.extend(&group.add);
+            members.retain(|user_id| !group.remove.contains(user_id));
+        }
+        members
+    }
+
+    // the ACL message a client edits the acl of a room with: the entries which apply to
+    // it, including inherited ones, and all the groups it sees, including inherited ones
+    pub fn acl_state(&self, rtbl: &RoutingTable, room_id: RoomID) -> msgs::ACL {
+        let room_acl = self.rooms.get(&room_id).cloned().unwrap_or_default();
+        let mut acl = msgs::ACL::new();
+        acl.set_channel_id(room_id);
+        acl.set_inherit_acls(room_acl.inherit);
+
+        let chain = self.chain(rtbl, room_id);
+        for acl_room_id in &chain {
+            let entries = self.rooms.get(acl_room_id).map(|room_acl| room_acl.entries.as_slice()).unwrap_or_default();
+            for entry in entries.iter().filter(|entry| *acl_room_id == room_id || entry.apply_subs) {
+                let mut chan_acl = msgs::ACL_ChanACL::new();
+                chan_acl.set_apply_here(entry.apply_here);
+                chan_acl.set_apply_subs(entry.apply_subs);
+                chan_acl.set_inherited(*acl_room_id != room_id);
+                if let Some(user_id) = entry.user_id {
+                    chan_acl.set_user_id(user_id);
+                }
+                if let Some(group) = &entry.group {
+                    chan_acl.set_group(group.clone());
+                }
+                chan_acl.set_grant(entry.grant);
+                chan_acl.set_deny(entry.deny);
+                acl.mut_acls().push(chan_acl);
+            }
+        }
+
+        // the groups defined here, and the inheritable ones of the rooms above
+        let mut names = BTreeSet::new();
+        for acl_room_id in &chain {
+            if let Some(room_acl) = self.rooms.get(acl_room_id) {
+                let groups = room_acl.groups.values();
+                names.extend(groups.filter(|group| *acl_room_id == room_id || group.inheritable).map(|group| group.name.clone()));
+            }
+        }
+        for name in names {
+            let mut chan_group = msgs::ACL_ChanGroup::new();
+            match room_acl.groups.get(&name) {
+                Some(group) => {
+                    chan_group.set_inherited(false);
+                    chan_group.set_inherit(group.inherit);
+                    chan_group.set_inheritable(group.inheritable);
+                    chan_group.set_add(group.add.iter().copied().collect());
+                    chan_group.set_remove(group.remove.iter().copied().collect());
+                },
+                None => {
+                    chan_group.set_inherited(true);
+                    chan_group.set_inherit(true);
+                    chan_group.set_inheritable(true);
+                },
+            }
+            if let Some(parent_id) = rtbl.room_parent(room_id) {
+                chan_group.set_inherited_members(self.members(rtbl, parent_id, &name).into_iter().collect());
+            }
+            chan_group.set_name(name);
+            acl.mut_groups().push(chan_group);
+        }
+        acl
+    }
+
+    // replace the acl of a room by the one a client sent. inherited entries/groups
+    // are sent back to us as they were, those are not the room's to change
+    pub fn set_acl_state(&mut self, room_id: RoomID, acl: &msgs::ACL) -> Result<()> {
+        let mut room_acl = RoomAcl{inherit: acl.get_inherit_acls(), ..RoomAcl::default()};
+
+        for chan_acl in acl.get_acls().iter().filter(|chan_acl| !chan_acl.get_inherited()) {
+            let (user_id, group) = match (chan_acl.has_user_id(), chan_acl.has_group()) {
+                (true, false) => (Some(chan_acl.get_user_id()), None),
+                (false, true) => (None, Some(chan_acl.get_group().to_owned())),
+                _ => return Err(Error::msg("acl entries apply to either a user or a group")),
+            };
+            room_acl.entries.push(AclEntry{
+                apply_here: chan_acl.get_apply_here(),
+                apply_subs: chan_acl.get_apply_subs(),
+                user_id,
+                group,
+                grant: chan_acl.get_grant() & ALL,
+                deny: chan_acl.get_deny() & ALL,
+            });
+        }
+
+        for chan_group in acl.get_groups() {
+            // untouched inherited groups are only there for display
+            let untouched = chan_group.get_inherit() && chan_group.get_inheritable()
+                && chan_group.get_add().is_empty() && chan_group.get_remove().is_empty();
+            if chan_group.get_inherited() && untouched {
+                continue
+            }
+            let name = chan_group.get_name();
+            if name.is_empty() || name.starts_with(|c| "!~$#".contains(c)) {
+                return Err(Error::msg(format!("bad group name {:?}", name)))
+            }
+            room_acl.groups.insert(name.to_owned(), Group{
+                name: name.to_owned(),
+                inherit: chan_group.get_inherit(),
+                inheritable: chan_group.get_inheritable(),
+                add: chan_group.get_add().iter().copied().collect(),
+                remove: chan_group.get_remove().iter().copied().collect(),
+            });
+        }
+
+        self.rooms.insert(room_id, room_acl);
+        Ok(())
+    }
+
+    // like murmur, make sure registered users keep control of the rooms they edit
+    pub fn grant_write(&mut self, room_id: RoomID, user_id: u32) {
+        let room_acl = self.rooms.entry(room_id).or_default();
+        room_acl.entries.push(AclEntry{
+            apply_here: true,
+            apply_subs: true,
+            user_id: Some(user_id),
+            group: None,
+            grant: WRITE | TRAVERSE,
+            deny: 0,
+        });
+    }
+}
+
+// "sub,a,b,c" holds the users in sub-rooms of the context room, where a is the number
+// of levels the context is offset by (0), and b/c the min/max depth of sub-rooms (1/1000)
+fn in_sub(rtbl: &RoutingTable, context: RoomID, name: &str, subject: &Subject) -> bool {
+    let mut args = name.split(',').skip(1).map(|arg| arg.trim().parse::<i64>().ok());
+    let min_path = args.next().flatten().unwrap_or(0);
+    let min_desc = args.next().flatten().unwrap_or(1);
+    let max_desc = args.next().flatten().unwrap_or(1000);
+
+    let ancestry = |room_id| {
+        let mut chain = vec![room_id];
+        while let Some(parent_id) = rtbl.room_parent(*chain.last().expect("never empty")) {
+            chain.push(parent_id);
+        }
+        chain.reverse();
+        chain
+    };
+    let subject_chain = ancestry(subject.room_id);
+    let context_chain = ancestry(context);
+
+    let offset = (context_chain.len() as i64 - 1 + min_path).max(0);
+    let needed = match context_chain.get(offset as usize) {
+        Some(needed) => needed,
+        None => return false,
+    };
+    if !subject_chain.contains(needed) {
+        return false
+    }
+    let depth = subject_chain.len() as i64 - 1;
+    depth >= offset + min_desc && depth <= offset + max_desc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::RoomRecord;
+
+    // rooms 0 > 1 > 2 > 3, and 0 > 4
+    fn rooms() -> RoutingTable {
+        let room = |id, parent| RoomRecord{
+            id, parent, name: format!("room {}", id), description: String::new(), position: 0, max_users: 0, links: vec![],
+        };
+        RoutingTable::restore(&[room(0, None), room(1, Some(0)), room(2, Some(1)), room(3, Some(2)), room(4, Some(0))])
+    }
+
+    // a session in each room, numbered after it, and a superuser session in the root
+    fn sessions() -> RoutingTable {
+        let mut rtbl = rooms();
+        let (sender, _) = crate::session_queue::session_queue(1);
+        for session_id in 0..=5 {
+            let user_id = if session_id == 5 { Some(SUPERUSER_ID) } else { None };
+            let addr = "127.0.0.1".parse().expect("valid address");
+            rtbl.enroll_session(session_id, format!("user {}", session_id), user_id, None, addr, msgs::Version::new(), sender.clone());
+            if session_id < 5 {
+                rtbl.move_session(session_id, session_id).expect("room exists");
+            }
+        }
+        rtbl
+    }
+
+    fn entry(group: &str, apply_here: bool, apply_subs: bool, grant: Permissions, deny: Permissions) -> AclEntry {
+        AclEntry{apply_here, apply_subs, user_id: None, group: Some(group.to_owned()), grant, deny}
+    }
+
+    fn with_entries(rooms: Vec<(RoomID, Vec<AclEntry>)>) -> Acl {
+        let records: Vec<AclRecord> = rooms.into_iter().map(|(room_id, entries)| {
+            AclRecord{room_id, inherit: true, entries, groups: vec![]}
+        }).collect();
+        Acl::restore(&records)
+    }
+
+    #[test]
+    fn inherited_deny_is_overridden_in_sub_rooms() {
+        let rtbl = sessions();
+        let acl = with_entries(vec![(0, vec![entry("all", true, true, 0, SPEAK)]), (2, vec![entry("all", true, true, SPEAK, 0)])]);
+        assert!(!acl.has_permission(&rtbl, 1, 0, SPEAK));
+        assert!(!acl.has_permission(&rtbl, 1, 1, SPEAK));
+        assert!(acl.has_permission(&rtbl, 1, 2, SPEAK));
+        assert!(acl.has_permission(&rtbl, 1, 3, SPEAK));
+        assert!(!acl.has_permission(&rtbl, 1, 4, SPEAK));
+    }
+
+    #[test]
+    fn entries_apply_where_they_say() {
+        let rtbl = sessions();
+        let acl = with_entries(vec![(1, vec![entry("all", false, true, MAKE_CHANNEL, ENTER)])]);
+        assert!(acl.has_permission(&rtbl, 0, 1, ENTER));
+        assert!(!acl.has_permission(&rtbl, 0, 1, MAKE_CHANNEL));
+        assert!(!acl.has_permission(&rtbl, 0, 2, ENTER));
+        assert!(acl.has_permission(&rtbl, 0, 3, MAKE_CHANNEL));
+
+        let acl = with_entries(vec![(1, vec![entry("all", true, false, 0, ENTER)])]);
+        assert!(!acl.has_permission(&rtbl, 0, 1, ENTER));
+        assert!(acl.has_permission(&rtbl, 0, 2, ENTER));
+    }
+
+    #[test]
+    fn traverse_deny_hides_the_subtree() {
+        let rtbl = sessions();
+        let acl = with_entries(vec![(1, vec![entry("all", true, true, 0, TRAVERSE)])]);
+        assert_eq!(acl.permissions(&rtbl, 0, 1), 0);
+        assert_eq!(acl.permissions(&rtbl, 0, 3), 0);
+        // not even a grant further down gets through
+        let mut acl = acl;
+        acl.rooms.insert(2, RoomAcl{entries: vec![entry("all", true, true, TRAVERSE, 0)], ..RoomAcl::default()});
+        assert_eq!(acl.permissions(&rtbl, 0, 3), 0);
+        assert_ne!(acl.permissions(&rtbl, 0, 4), 0);
+        // unless it is write
+        acl.grant_write(1, 7);
+        let mut rtbl = rtbl;
+        rtbl.set_user_id(0, 7).expect("session exists");
+        assert!(acl.has_permission(&rtbl, 0, 3, WRITE | ENTER));
+    }
+
+    #[test]
+    fn sub_groups_count_levels_below_their_room() {
+        let rtbl = sessions();
+        // the sessions in rooms 2 and 3 are in sub-rooms of room 1, only the one in 3 two levels below
+        let acl = with_entries(vec![
+            (1, vec![entry("~sub", true, true, MAKE_CHANNEL, 0), entry("~sub,0,2", true, true, LINK_CHANNEL, 0)]),
+        ]);
+        for (session_id, make_channel, link_channel) in [(0, false, false), (1, false, false), (2, true, false), (3, true, true), (4, false, false)] {
+            assert_eq!(acl.has_permission(&rtbl, session_id, 2, MAKE_CHANNEL), make_channel, "session {}", session_id);
+            assert_eq!(acl.has_permission(&rtbl, session_id, 2, LINK_CHANNEL), link_channel, "session {}", session_id);
+        }
+        // without the ~, sub-rooms are those of the room permissions are computed for
+        let acl = with_entries(vec![(1, vec![entry("sub", true, true, MAKE_CHANNEL, 0)])]);
+        assert!(!acl.has_permission(&rtbl, 2, 2, MAKE_CHANNEL));
+        assert!(acl.has_permission(&rtbl, 3, 2, MAKE_CHANNEL));
+    }
+
+    #[test]
+    fn superuser_may_do_anything_but_talk() {
+        let rtbl = sessions();
+        let acl = with_entries(vec![(0, vec![entry("all", true, true, 0, ALL)])]);
+        assert_eq!(acl.permissions(&rtbl, 5, 3), ALL & !(SPEAK | WHISPER));
+        assert_eq!(acl.permissions(&rtbl, 4, 3), 0);
+    }
+}
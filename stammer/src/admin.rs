@@ -0,0 +1,322 @@
+use anyhow::{Error,Result};
+use std::sync::Arc;
+use serde::Deserialize;
+use serde_json::{json,Value};
+use tokio::sync::oneshot;
+use tokio::sync::mpsc::UnboundedSender as USender;
+use hyper::{Body,Method,Request,Response,StatusCode};
+use log::{info,warn};
+use super::local_socket::LocalListener;
+use super::task_control::ControlMessage;
+
+// what scripts may ask of a running stammer. the control task carries these out the
+// way it does for clients, only with every permission
+#[derive(Debug)]
+pub enum AdminRequest {
+    ListSessions,
+    Kick{session: u32, reason: String, ban: bool},
+    Move{session: u32, channel: u32},
+    CreateChannel{parent: u32, name: String},
+    RemoveChannel{channel: u32},
+    // to the given sessions, channels and channel trees, or to everyone if none are given
+    Message{text: String, sessions: Vec<u32>, channels: Vec<u32>, trees: Vec<u32>},
+    DumpRouting,
+}
+
+// the control task answers with json, or with why it could not do it
+pub type AdminReply = oneshot::Sender<Result<Value>>;
+
+// serves the admin api until the process stops. requests must carry the token in an
+// "Authorization: Bearer <token>" header, over tcp and unix sockets alike
+pub async fn run_admin_task(listener: LocalListener, token: String, control_send: USender<ControlMessage>) {
+    use hyper::server::accept::from_stream;
+    info!("serving admin api on {}", listener.describe());
+    let state = Arc::new((token, control_send));
+    let served = match listener {
+        LocalListener::Tcp(listener) => serve(from_stream(listener), state).await,
+        LocalListener::Unix(listener) => serve(from_stream(listener), state).await,
+    };
+    if let Err(err) = served {
+        warn!("admin api stopped: {}", err);
+    }
+}
+
+// the same service over either kind of connection
+async fn serve<I>(incoming: I, state: Arc<(String, USender<ControlMessage>)>) -> hyper::Result<()>
+where
+    I: hyper::server::accept::Accept,
+    I::Conn: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    use std::convert::Infallible;
+    use hyper::Server;
+    use hyper::service::{make_service_fn,service_fn};
+    let make_service = make_service_fn(move |_: &I::Conn| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle_request(req, &state.0, &state.1).await) }
+            }))
+        }
+    });
+    Server::builder(incoming).serve(make_service).await
+}
+
+async fn handle_request(req: Request<Body>, token: &str, control_send: &USender<ControlMessage>) -> Response<Body> {
+    let authorized = req.headers().get("Authorization").and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given.len() == token.len() && openssl::memcmp::eq(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return reply(StatusCode::UNAUTHORIZED, json!({"error": "missing or wrong token"}))
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(err) => return reply(StatusCode::BAD_REQUEST, json!({"error": err.to_string()})),
+    };
+    let request = match parse_request(&method, &path, &body) {
+        Ok(Some(request)) => request,
+        Ok(None) => return reply(StatusCode::NOT_FOUND, json!({"error": format!("no such endpoint: {} {}", method, path)})),
+        Err(err) => return reply(StatusCode::BAD_REQUEST, json!({"error": err.to_string()})),
+    };
+
+    info!("admin api: {:?}", request);
+    let (reply_send, reply_recv) = oneshot::channel();
+    if control_send.send(ControlMessage::Admin(request, reply_send)).is_err() {
+        return reply(StatusCode::SERVICE_UNAVAILABLE, json!({"error": "stammer is shutting down"}))
+    }
+    match reply_recv.await {
+        Ok(Ok(value)) => reply(StatusCode::OK, value),
+        Ok(Err(err)) => reply(StatusCode::BAD_REQUEST, json!({"error": err.to_string()})),
+        Err(_) => reply(StatusCode::SERVICE_UNAVAILABLE, json!({"error": "stammer is shutting down"})),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KickBody {
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MoveBody {
+    channel: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelBody {
+    #[serde(default)]
+    parent: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageBody {
+    text: String,
+    #[serde(default)]
+    sessions: Vec<u32>,
+    #[serde(default)]
+    channels: Vec<u32>,
+    #[serde(default)]
+    trees: Vec<u32>,
+}
+
+// the endpoints, bodies being json objects:
+//
+//  GET    /sessions                 sessions with their name, channel, address, version and
+//                                   how many voice packets they dropped
+//  POST   /sessions/<id>/kick       {"reason": ...}
+//  POST   /sessions/<id>/ban        {"reason": ...}
+//  POST   /sessions/<id>/move       {"channel": <id>}
+//  POST   /channels                 {"parent": <id>, "name": ...}, yields the new channel id
+//  DELETE /channels/<id>
+//  POST   /messages                 {"text": ..., "sessions"/"channels"/"trees": [<id>...]}
+//  GET    /routing                  the routing table as the control task sees it
+fn parse_request(method: &Method, path: &str, body: &[u8]) -> Result<Option<AdminRequest>> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let id = |segment: &str| segment.parse::<u32>().map_err(|_| Error::msg(format!("bad id {:?}", segment)));
+    let request = match (method, segments.as_slice()) {
+        (&Method::GET, ["sessions"]) => AdminRequest::ListSessions,
+        (&Method::POST, ["sessions", session, action @ ("kick" | "ban")]) => {
+            let body: KickBody = json_body(body)?;
+            AdminRequest::Kick{session: id(session)?, reason: body.reason, ban: *action == "ban"}
+        },
+        (&Method::POST, ["sessions", session, "move"]) => {
+            let body: MoveBody = json_body(body)?;
+            AdminRequest::Move{session: id(session)?, channel: body.channel}
+        },
+        (&Method::POST, ["channels"]) => {
+            let body: ChannelBody = json_body(body)?;
+            AdminRequest::CreateChannel{parent: body.parent, name: body.name}
+        },
+        (&Method::DELETE, ["channels", channel]) => AdminRequest::RemoveChannel{channel: id(channel)?},
+        (&Method::POST, ["messages"]) => {
+            let body: MessageBody = json_body(body)?;
+            AdminRequest::Message{text: body.text, sessions: body.sessions, channels: body.channels, trees: body.trees}
+        },
+        (&Method::GET, ["routing"]) => AdminRequest::DumpRouting,
+        _ => return Ok(None),
+    };
+    Ok(Some(request))
+}
+
+// bodies are optional where all of their fields are
+fn json_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T> {
+    let body = if body.is_empty() { b"{}" } else { body };
+    Ok(serde_json::from_slice(body)?)
+}
+
+fn reply(status: StatusCode, value: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(format!("{}\n", value)))
+        .expect("valid response")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(method: Method, path: &str, body: &str) -> Result<Option<AdminRequest>> {
+        parse_request(&method, path, body.as_bytes())
+    }
+
+    #[test]
+    fn endpoints_parse_into_requests() {
+        assert!(matches!(parse(Method::GET, "/sessions", ""), Ok(Some(AdminRequest::ListSessions))));
+        assert!(matches!(
+            parse(Method::POST, "/sessions/3/kick", r#"{"reason": "spam"}"#),
+            Ok(Some(AdminRequest::Kick{session: 3, ref reason, ban: false})) if reason == "spam"
+        ));
+        assert!(matches!(
+            parse(Method::POST, "/sessions/3/ban", ""),
+            Ok(Some(AdminRequest::Kick{session: 3, ref reason, ban: true})) if reason.is_empty()
+        ));
+        assert!(matches!(
+            parse(Method::POST, "/sessions/3/move", r#"{"channel": 2}"#),
+            Ok(Some(AdminRequest::Move{session: 3, channel: 2}))
+        ));
+        assert!(matches!(
+            parse(Method::POST, "/channels", r#"{"name": "lobby"}"#),
+            Ok(Some(AdminRequest::CreateChannel{parent: 0, ref name})) if name == "lobby"
+        ));
+        assert!(matches!(
+            parse(Method::DELETE, "/channels/4/", ""),
+            Ok(Some(AdminRequest::RemoveChannel{channel: 4}))
+        ));
+        assert!(matches!(
+            parse(Method::POST, "/messages", r#"{"text": "hi", "channels": [1, 2]}"#),
+            Ok(Some(AdminRequest::Message{ref text, ref sessions, ref channels, ref trees}))
+                if text == "hi" && sessions.is_empty() && channels == &[1, 2] && trees.is_empty()
+        ));
+        assert!(matches!(parse(Method::GET, "/routing", ""), Ok(Some(AdminRequest::DumpRouting))));
+    }
+
+    #[test]
+    fn unknown_methods_and_paths_are_not_endpoints() {
+        let unknown = [
+            (Method::POST, "/sessions"),
+            (Method::GET, "/sessions/3/kick"),
+            (Method::PUT, "/channels"),
+            (Method::DELETE, "/channels"),
+            (Method::POST, "/sessions/3/mute"),
+            (Method::GET, "/sessions/3/move/4"),
+            (Method::GET, "/"),
+            (Method::GET, "/metrics"),
+        ];
+        for (method, path) in &unknown {
+            assert!(matches!(parse(method.clone(), path, ""), Ok(None)), "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn bad_ids_and_bodies_are_refused() {
+        let bad = [
+            (Method::POST, "/sessions/alice/kick", ""),
+            (Method::DELETE, "/channels/-1", ""),
+            (Method::POST, "/sessions/3/kick", "reason=spam"),
+            (Method::POST, "/sessions/3/kick", r#"{"reason": "spam", "ban": true}"#),
+            (Method::POST, "/sessions/3/move", ""),
+            (Method::POST, "/sessions/3/move", r#"{"channel": "lobby"}"#),
+            (Method::POST, "/channels", r#"{"parent": 0}"#),
+            (Method::POST, "/messages", r#"{"sessions": [1]}"#),
+            (Method::POST, "/messages", r#""hi""#),
+        ];
+        for (method, path, body) in &bad {
+            assert!(parse(method.clone(), path, body).is_err(), "{} {} {}", method, path, body);
+        }
+    }
+
+    #[test]
+    fn empty_bodies_stand_for_empty_objects() {
+        let body: KickBody = json_body(b"").expect("all fields are optional");
+        assert_eq!(body.reason, "");
+        assert!(json_body::<MoveBody>(b"").is_err());
+        assert!(json_body::<MessageBody>(b"{}").is_err());
+    }
+
+    async fn request(method: &str, path: &str, token: Option<&str>, body: &str, control_send: &USender<ControlMessage>) -> (StatusCode, Value) {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        let req = req.body(Body::from(body.to_owned())).expect("valid request");
+        let resp = handle_request(req, "sekrit", control_send).await;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.expect("whole body");
+        (status, serde_json::from_slice(&body).expect("json body"))
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        let (control_send, _control_recv) = tokio::sync::mpsc::unbounded_channel();
+        for token in &[None, Some(""), Some("sekri"), Some("sekrit2"), Some("SEKRIT")] {
+            let (status, value) = request("GET", "/sessions", *token, "", &control_send).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(value, json!({"error": "missing or wrong token"}));
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_are_json() {
+        use tokio::stream::StreamExt;
+        let (control_send, mut control_recv) = tokio::sync::mpsc::unbounded_channel();
+        // stands in for the control task
+        tokio::spawn(async move {
+            while let Some(msg) = control_recv.next().await {
+                if let ControlMessage::Admin(request, reply) = msg {
+                    let _ = reply.send(match request {
+                        AdminRequest::ListSessions => Ok(json!({"sessions": []})),
+                        request => Err(Error::msg(format!("cannot {:?}", request))),
+                    });
+                }
+            }
+        });
+
+        let (status, value) = request("GET", "/sessions", Some("sekrit"), "", &control_send).await;
+        assert_eq!((status, value), (StatusCode::OK, json!({"sessions": []})));
+        let (status, value) = request("DELETE", "/channels/0", Some("sekrit"), "", &control_send).await;
+        assert_eq!((status, value), (StatusCode::BAD_REQUEST, json!({"error": "cannot RemoveChannel { channel: 0 }"})));
+        let (status, value) = request("GET", "/nope", Some("sekrit"), "", &control_send).await;
+        assert_eq!((status, value), (StatusCode::NOT_FOUND, json!({"error": "no such endpoint: GET /nope"})));
+        let (status, value) = request("POST", "/sessions/1/move", Some("sekrit"), "{}", &control_send).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(value["error"].as_str().is_some_and(|err| err.contains("channel")), "{}", value);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_served_once_the_control_task_is_gone() {
+        let (control_send, control_recv) = tokio::sync::mpsc::unbounded_channel();
+        drop(control_recv);
+        let (status, value) = request("GET", "/sessions", Some("sekrit"), "", &control_send).await;
+        assert_eq!((status, value), (StatusCode::SERVICE_UNAVAILABLE, json!({"error": "stammer is shutting down"})));
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{Error,Result};
+use chrono::{DateTime,NaiveDateTime,Utc};
+use serde::{Deserialize,Serialize};
+use std::net::{IpAddr,Ipv6Addr};
+use mumble_protocol::control::msgs;
+
+// how ban start times travel in BanList, like murmur's (utc, no offset)
+const START_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+// a banned address range, and the certificate of the banned user if it had one. like
+// murmur, a ban applies to connections from within the range or with the certificate
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    // ipv4 addresses are kept as such, their mask counting ipv4 bits
+    pub address: IpAddr,
+    pub mask: u32,
+    // the banned user's name and the reason, for the record only
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub cert_hash: Option<String>,
+    #[serde(default)]
+    pub reason: String,
+    pub start: DateTime<Utc>,
+    // in seconds, 0 means forever
+    #[serde(default)]
+    pub duration: u32,
+}
+
+impl Ban {
+    // the ban of a single address, along with the certificate connecting from it
+    pub fn new(address: IpAddr, name: String, cert_hash: Option<String>, reason: String) -> Self {
+        let mask = if address.is_ipv4() { 32 } else { 128 };
+        Self{address, mask, name, cert_hash, reason, start: Utc::now(), duration: 0}
+    }
+
+    // clients send ipv4 addresses mapped into ipv6, masks counting ipv6 bits
+    fn from_entry(entry: &msgs::BanList_BanEntry) -> Result<Self> {
+        use std::convert::TryFrom;
+        let address = match entry.get_address().len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(entry.get_address()).expect("checked length")),
+            16 => IpAddr::from(<[u8; 16]>::try_from(entry.get_address()).expect("checked length")),
+            len => return Err(Error::msg(format!("bad ban address length {}", len))),
+        };
+        let (address, mask) = match address {
+            // masks below 96 would reach out of the mapped range, into all of ipv4 and beyond
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_some() => match entry.get_mask().checked_sub(96) {
+                Some(mask) => (IpAddr::V4(v6.to_ipv4_mapped().expect("checked above")), mask),
+                None => return Err(Error::msg(format!("bad mask {} for ipv4-mapped address {}", entry.get_mask(), v6))),
+            },
+            address => (address, entry.get_mask()),
+        };
+        let start = match entry.has_start() {
+            true => NaiveDateTime::parse_from_str(entry.get_start(), START_FORMAT)?.and_utc(),
+            false => Utc::now(),
+        };
+
+        let ban = Self{
+            address,
+            mask,
+            name: entry.get_name().to_owned(),
+            cert_hash: Some(entry.get_hash().to_owned()).filter(|hash| !hash.is_empty()),
+            reason: entry.get_reason().to_owned(),
+            start,
+            duration: entry.get_duration(),
+        };
+        ban.check()?;
+        Ok(ban)
+    }
+
+    fn entry(&self) -> msgs::BanList_BanEntry {
+        let mut entry = msgs::BanList_BanEntry::new();
+        let (address, mask) = self.range();
+        entry.set_address(Ipv6Addr::from(address).octets().to_vec());
+        entry.set_mask(mask);
+        entry.set_name(self.name.clone());
+        if let Some(cert_hash) = &self.cert_hash {
+            entry.set_hash(cert_hash.clone());
+        }
+        entry.set_reason(self.reason.clone());
+        entry.set_start(self.start.format(START_FORMAT).to_string());
+        entry.set_duration(self.duration);
+        entry
+    }
+
+    pub fn check(&self) -> Result<()> {
+        let max_mask = if self.address.is_ipv4() { 32 } else { 128 };
+        if self.mask > max_mask {
+            return Err(Error::msg(format!("bad mask {} for address {}", self.mask, self.address)))
+        }
+        Ok(())
+    }
+
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.duration != 0 && now >= self.start + chrono::Duration::seconds(self.duration.into())
+    }
+
+    // the banned range as an ipv6 address and mask, ipv4 being mapped into it
+    fn range(&self) -> (u128, u32) {
+        match self.address {
+            IpAddr::V4(v4) => (u128::from(v4.to_ipv6_mapped()), self.mask + 96),
+            IpAddr::V6(v6) => (u128::from(v6), self.mask),
+        }
+    }
+
+    fn covers(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+            IpAddr::V6(v6) => u128::from(v6),
+        };
+        let (range, mask) = self.range();
+        let mask = u128::MAX.checked_shl(128 - mask).unwrap_or(0);
+        address & mask == range & mask
+    }
+}
+
+// the bans in place. the accept task holds a copy to turn away banned addresses before
+// anything else happens, the control task turns away banned certificates upon authentication
+#[derive(Clone, Debug, Default)]
+pub struct BanList {
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    pub fn restore(records: &[Ban]) -> Self {
+        let mut bans = Self{bans: records.to_vec()};
+        bans.prune();
+        bans
+    }
+
+    // bans which have not expired yet. expired ones are pruned whenever the list
+    // changes, some may have expired since then
+    pub fn records(&self) -> Vec<Ban> {
+        let now = Utc::now();
+        self.bans.iter().filter(|ban| !ban.expired(now)).cloned().collect()
+    }
+
+    pub fn add(&mut self, ban: Ban) {
+        self.prune();
+        self.bans.push(ban);
+    }
+
+    // so that neither our list nor the accept task's copy grow with dead bans
+    fn prune(&mut self) {
+        let now = Utc::now();
+        self.bans.retain(|ban| !ban.expired(now));
+    }
+
+    // the ban keeping a connection out, if any
+    pub fn find(&self, address: IpAddr, cert_hash: Option<&str>) -> Option<&Ban> {
+        let now = Utc::now();
+        self.bans.iter().filter(|ban| !ban.expired(now)).find(|ban| {
+            ban.covers(address) || (ban.cert_hash.is_some() && ban.cert_hash.as_deref() == cert_hash)
+        })
+    }
+
+    pub fn ban_list(&self) -> msgs::BanList {
+        let mut ban_list = msgs::BanList::new();
+        ban_list.set_bans(self.records().iter().map(Ban::entry).collect());
+        ban_list
+    }
+
+    // clients send the whole list back edited, which replaces ours if all of it is sound
+    pub fn set_ban_list(&mut self, ban_list: &msgs::BanList) -> Result<()> {
+        self.bans = ban_list.get_bans().iter().map(Ban::from_entry).collect::<Result<_>>()?;
+        self.prune();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ban(address: &str, mask: u32) -> Ban {
+        let mut ban = Ban::new(address.parse().unwrap(), "name".to_owned(), None, "reason".to_owned());
+        ban.mask = mask;
+        ban
+    }
+
+    fn entry(address: IpAddr, mask: u32) -> msgs::BanList_BanEntry {
+        let mut entry = msgs::BanList_BanEntry::new();
+        let octets = match address {
+            IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+            IpAddr::V6(v6) => v6.octets(),
+        };
+        entry.set_address(octets.to_vec());
+        entry.set_mask(mask);
+        entry
+    }
+
+    #[test]
+    fn ipv4_ranges_cover_their_addresses_only() {
+        let ban = ban("10.1.2.3", 16);
+        assert!(ban.covers("10.1.0.1".parse().unwrap()));
+        assert!(ban.covers("10.1.255.255".parse().unwrap()));
+        assert!(!ban.covers("10.2.0.1".parse().unwrap()));
+        // as clients connecting over ipv6 sockets show up
+        assert!(ban.covers("::ffff:10.1.9.9".parse().unwrap()));
+        assert!(!ban.covers("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn mask_edges() {
+        assert!(ban("10.1.2.3", 32).covers("10.1.2.3".parse().unwrap()));
+        assert!(!ban("10.1.2.3", 32).covers("10.1.2.4".parse().unwrap()));
+        // all of ipv4, but nothing else
+        assert!(ban("10.1.2.3", 0).covers("192.168.0.1".parse().unwrap()));
+        assert!(!ban("10.1.2.3", 0).covers("2001:db8::1".parse().unwrap()));
+        assert!(ban("2001:db8::1", 128).covers("2001:db8::1".parse().unwrap()));
+        assert!(!ban("2001:db8::1", 128).covers("2001:db8::2".parse().unwrap()));
+        assert!(ban("2001:db8::1", 0).covers("10.0.0.1".parse().unwrap()));
+        assert!(ban("2001:db8::1", 32).covers("2001:db8:ffff::".parse().unwrap()));
+    }
+
+    #[test]
+    fn entries_map_ipv4_back() {
+        let ban = Ban::from_entry(&entry("10.1.2.3".parse().unwrap(), 120)).unwrap();
+        assert_eq!(ban.address, "10.1.2.3".parse::<IpAddr>().unwrap());
+        assert_eq!(ban.mask, 24);
+        assert_eq!(ban.range().1, 120);
+        assert_eq!(Ban::from_entry(&ban.entry()).unwrap().mask, 24);
+
+        let ban = Ban::from_entry(&entry("2001:db8::1".parse().unwrap(), 64)).unwrap();
+        assert_eq!(ban.address, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(ban.mask, 64);
+    }
+
+    #[test]
+    fn bad_entries_are_refused() {
+        // would ban all of ipv4
+        assert!(Ban::from_entry(&entry("10.1.2.3".parse().unwrap(), 64)).is_err());
+        assert!(Ban::from_entry(&entry("10.1.2.3".parse().unwrap(), 129)).is_err());
+        assert!(Ban::from_entry(&entry("2001:db8::1".parse().unwrap(), 129)).is_err());
+        let mut short = entry("10.1.2.3".parse().unwrap(), 128);
+        short.set_address(vec![10, 1, 2]);
+        assert!(Ban::from_entry(&short).is_err());
+        let mut bad_start = entry("10.1.2.3".parse().unwrap(), 128);
+        bad_start.set_start("yesterday".to_owned());
+        assert!(Ban::from_entry(&bad_start).is_err());
+    }
+
+    #[test]
+    fn bans_expire_after_their_duration() {
+        let mut ban = ban("10.1.2.3", 32);
+        let now = ban.start;
+        assert!(!ban.expired(now + chrono::Duration::days(10000)));
+        ban.duration = 60;
+        assert!(!ban.expired(now + chrono::Duration::seconds(59)));
+        assert!(ban.expired(now + chrono::Duration::seconds(60)));
+    }
+
+    #[test]
+    fn expired_bans_are_pruned() {
+        let mut expired = ban("10.1.2.3", 32);
+        expired.start = Utc::now() - chrono::Duration::hours(1);
+        expired.duration = 60;
+        let mut bans = BanList::restore(&[expired]);
+        assert!(bans.find("10.1.2.3".parse().unwrap(), None).is_none());
+        bans.add(ban("10.9.9.9", 32));
+        assert_eq!(bans.bans.len(), 1);
+        assert!(bans.find("10.9.9.9".parse().unwrap(), None).is_some());
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::{Error,Result};
+use serde::Deserialize;
+use std::path::{Path,PathBuf};
+use std::time::Duration;
+use log::warn;
+use regex::Regex;
+use super::{StammerConfig,TlsConfig,parse_version};
+use super::events::parse_webhook;
+use super::metrics::parse_metrics_addr;
+
+// the config file, in toml. every setting is optional, missing ones come from the
+// environment (STAMMER_*) or their defaults. unknown keys are refused, as they are
+// most likely typos an operator would rather hear about
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bind_addr: Option<String>,
+    tls: Option<TlsFile>,
+    storage_path: Option<PathBuf>,
+    superuser_password: Option<String>,
+    welcome_text: Option<String>,
+    max_users: Option<u32>,
+    max_bandwidth: Option<u32>,
+    session_timeout_secs: Option<u64>,
+    max_saturation_secs: Option<u64>,
+    session_queue_size: Option<usize>,
+    max_frame_len: Option<usize>,
+    max_text_len: Option<usize>,
+    max_image_len: Option<usize>,
+    allow_html: Option<bool>,
+    min_client_version: Option<String>,
+    username_regex: Option<String>,
+    max_username_len: Option<usize>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+    log_output: Option<String>,
+    metrics_addr: Option<String>,
+    admin_addr: Option<String>,
+    admin_token: Option<String>,
+    event_socket: Option<String>,
+    event_webhooks: Option<Vec<String>>,
+    event_queue_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsFile {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl StammerConfig {
+    // from the config file if STAMMER_CONFIG_PATH points to one, from the environment otherwise
+    pub fn load() -> Result<Self> {
+        match Self::file_path() {
+            Some(path) => Self::from_file(&path),
+            None => Self::from_env(),
+        }
+    }
+
+    pub fn file_path() -> Option<PathBuf> {
+        std::env::var_os("STAMMER_CONFIG_PATH").map(PathBuf::from)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            Error::msg(format!("failed to read {}: {}", path.display(), err))
+        })?;
+        let file: ConfigFile = toml::from_str(&contents).map_err(|err| {
+            Error::msg(format!("{}: {}", path.display(), err))
+        })?;
+        let mut stammer_cfg = Self::read_env()?;
+        file.apply(&mut stammer_cfg).map_err(|err| Error::msg(format!("{}: {}", path.display(), err)))?;
+        stammer_cfg.validate().map_err(|err| Error::msg(format!("{}: {}", path.display(), err)))?;
+        Ok(stammer_cfg)
+    }
+
+    // what a reload changes: everything but the listening addresses, the admin token, the
+    // event sinks, tls, storage and where and how logs are written, which are only set up
+    // at startup. the queue size and frame length apply to new sessions
+    pub fn reloaded(&self, new: Self) -> Self {
+        if new.bind_addr != self.bind_addr {
+            warn!("bind_addr changed, it only applies upon restart");
+        }
+        if new.tls != self.tls {
+            warn!("tls changed, it only applies upon restart");
+        }
+        if new.storage_path != self.storage_path {
+            warn!("storage_path changed, it only applies upon restart");
+        }
+        if new.log_format != self.log_format || new.log_output != self.log_output {
+            warn!("log_format or log_output changed, they only apply upon restart");
+        }
+        if new.metrics_addr != self.metrics_addr {
+            warn!("metrics_addr changed, it only applies upon restart");
+        }
+        if new.admin_addr != self.admin_addr || new.admin_token != self.admin_token {
+            warn!("admin_addr or admin_token changed, they only apply upon restart");
+        }
+        let events_changed = new.event_socket != self.event_socket
+            || new.event_webhooks != self.event_webhooks || new.event_queue_size != self.event_queue_size;
+        if events_changed {
+            warn!("event_socket, event_webhooks or event_queue_size changed, they only apply upon restart");
+        }
+        Self{
+            bind_addr: self.bind_addr.clone(),
+            metrics_addr: self.metrics_addr,
+            admin_addr: self.admin_addr.clone(),
+            admin_token: self.admin_token.clone(),
+            event_socket: self.event_socket.clone(),
+            event_webhooks: self.event_webhooks.clone(),
+            event_queue_size: self.event_queue_size,
+            tls: self.tls.clone(),
+            storage_path: self.storage_path.clone(),
+            log_format: self.log_format,
+            log_output: self.log_output.clone(),
+            ..new
+        }
+    }
+}
+
+impl ConfigFile {
+    // errors name the offending key, the file is where operators will go look
+    fn apply(self, stammer_cfg: &mut StammerConfig) -> Result<()> {
+        if let Some(bind_addr) = self.bind_addr {
+            stammer_cfg.bind_addr = bind_addr;
+        }
+        if let Some(tls) = self.tls {
+            stammer_cfg.tls = Some(TlsConfig{cert_path: tls.cert_path, key_path: tls.key_path});
+        }
+        if let Some(storage_path) = self.storage_path {
+            stammer_cfg.storage_path = Some(storage_path);
+        }
+        if let Some(superuser_password) = self.superuser_password {
+            stammer_cfg.superuser_password = Some(superuser_password);
+        }
+        if let Some(welcome_text) = self.welcome_text {
+            stammer_cfg.welcome_text = welcome_text;
+        }
+        if let Some(max_users) = self.max_users {
+            stammer_cfg.max_users = max_users;
+        }
+        if let Some(max_bandwidth) = self.max_bandwidth {
+            stammer_cfg.max_bandwidth = max_bandwidth;
+        }
+        if let Some(secs) = self.session_timeout_secs {
+            stammer_cfg.session_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.max_saturation_secs {
+            stammer_cfg.max_saturation = Duration::from_secs(secs);
+        }
+        if let Some(session_queue_size) = self.session_queue_size {
+            stammer_cfg.session_queue_size = session_queue_size;
+        }
+        if let Some(max_frame_len) = self.max_frame_len {
+            stammer_cfg.max_frame_len = max_frame_len;
+        }
+        if let Some(max_text_len) = self.max_text_len {
+            stammer_cfg.max_text_len = max_text_len;
+        }
+        if let Some(max_image_len) = self.max_image_len {
+            stammer_cfg.max_image_len = max_image_len;
+        }
+        if let Some(allow_html) = self.allow_html {
+            stammer_cfg.allow_html = allow_html;
+        }
+        if let Some(version) = self.min_client_version {
+            stammer_cfg.min_client_version = parse_version(&version).map_err(|err| {
+                Error::msg(format!("min_client_version: {}", err))
+            })?;
+        }
+        if let Some(username_regex) = self.username_regex {
+            stammer_cfg.username_regex = Regex::new(&format!("^(?:{})$", username_regex)).map_err(|err| {
+                Error::msg(format!("username_regex: {}", err))
+            })?;
+        }
+        if let Some(max_username_len) = self.max_username_len {
+            stammer_cfg.max_username_len = max_username_len;
+        }
+        if let Some(log_level) = self.log_level {
+            stammer_cfg.log_levels = log_level.parse().map_err(|err| {
+                Error::msg(format!("log_level: {}", err))
+            })?;
+        }
+        if let Some(log_format) = self.log_format {
+            stammer_cfg.log_format = log_format.parse().map_err(|err| {
+                Error::msg(format!("log_format: {}", err))
+            })?;
+        }
+        if let Some(log_output) = self.log_output {
+            stammer_cfg.log_output = log_output.parse().map_err(|err| {
+                Error::msg(format!("log_output: {}", err))
+            })?;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            stammer_cfg.metrics_addr = Some(parse_metrics_addr(&metrics_addr).map_err(|err| {
+                Error::msg(format!("metrics_addr: {}", err))
+            })?);
+        }
+        if let Some(admin_addr) = self.admin_addr {
+            stammer_cfg.admin_addr = Some(admin_addr.parse().map_err(|err| {
+                Error::msg(format!("admin_addr: {}", err))
+            })?);
+        }
+        if let Some(admin_token) = self.admin_token {
+            stammer_cfg.admin_token = Some(admin_token);
+        }
+        if let Some(event_socket) = self.event_socket {
+            stammer_cfg.event_socket = Some(event_socket.parse().map_err(|err| {
+                Error::msg(format!("event_socket: {}", err))
+            })?);
+        }
+        if let Some(event_webhooks) = self.event_webhooks {
+            stammer_cfg.event_webhooks = event_webhooks.iter().map(|url| parse_webhook(url)).collect::<Result<_>>()
+                .map_err(|err| Error::msg(format!("event_webhooks: {}", err)))?;
+        }
+        if let Some(event_queue_size) = self.event_queue_size {
+            stammer_cfg.event_queue_size = event_queue_size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the defaults, as long as the environment sets nothing
+    fn defaults() -> StammerConfig {
+        StammerConfig::read_env().expect("valid defaults")
+    }
+
+    fn applied(contents: &str) -> Result<StammerConfig> {
+        let file: ConfigFile = toml::from_str(contents)?;
+        let mut stammer_cfg = defaults();
+        file.apply(&mut stammer_cfg)?;
+        Ok(stammer_cfg)
+    }
+
+    #[test]
+    fn files_override_what_they_set() {
+        let stammer_cfg = applied(r#"
+            max_users = 5
+            welcome_text = "hi"
+            session_timeout_secs = 10
+            min_client_version = "1.3.0"
+            admin_addr = "unix:/run/stammer/admin.sock"
+            event_webhooks = ["http://127.0.0.1:8000/hook"]
+            [tls]
+            cert_path = "cert.pem"
+            key_path = "key.pem"
+        "#).expect("valid config");
+        assert_eq!(stammer_cfg.max_users, 5);
+        assert_eq!(stammer_cfg.welcome_text, "hi");
+        assert_eq!(stammer_cfg.session_timeout, Duration::from_secs(10));
+        assert_eq!(stammer_cfg.min_client_version, 0x010300);
+        assert_eq!(stammer_cfg.admin_addr, Some("unix:/run/stammer/admin.sock".parse().expect("valid address")));
+        assert_eq!(stammer_cfg.event_webhooks.len(), 1);
+        assert_eq!(stammer_cfg.tls, Some(TlsConfig{cert_path: "cert.pem".into(), key_path: "key.pem".into()}));
+        // the rest is left as is
+        assert_eq!(stammer_cfg.session_queue_size, defaults().session_queue_size);
+        assert_eq!(stammer_cfg.bind_addr, defaults().bind_addr);
+        assert!(stammer_cfg.username_regex.is_match("alice"));
+    }
+
+    #[test]
+    fn file_errors_name_the_key() {
+        assert!(toml::from_str::<ConfigFile>("max_user = 5").is_err());
+        assert!(toml::from_str::<ConfigFile>("max_users = \"5\"").is_err());
+        let bad_values = [
+            ("min_client_version", "\"1.2\""),
+            ("username_regex", "\"(\""),
+            ("log_format", "\"xml\""),
+            ("metrics_addr", "\"0.0.0.0:9100\""),
+            ("admin_addr", "\"localhost\""),
+            ("event_socket", "\"nowhere\""),
+            ("event_webhooks", "[\"ftp://127.0.0.1/hook\"]"),
+        ];
+        for (key, value) in &bad_values {
+            let err = applied(&format!("{} = {}", key, value)).expect_err("bad value");
+            assert!(err.to_string().starts_with(&format!("{}: ", key)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn validation_catches_what_would_fail_later() {
+        defaults().validate().expect("valid defaults");
+        for contents in &["session_timeout_secs = 0", "session_queue_size = 0", "event_queue_size = 0"] {
+            let err = applied(contents).expect("valid config").validate().expect_err("invalid setting");
+            assert!(err.to_string().contains(contents.split(' ').next().expect("key")), "{}", err);
+        }
+        let admin = "admin_addr = \"127.0.0.1:8793\"";
+        assert!(applied(admin).expect("valid config").validate().is_err());
+        assert!(applied(&format!("{}\nadmin_token = \"\"", admin)).expect("valid config").validate().is_err());
+        applied(&format!("{}\nadmin_token = \"sekrit\"", admin)).expect("valid config").validate().expect("valid admin api");
+    }
+
+    #[test]
+    fn reloads_keep_what_is_set_up_at_startup() {
+        let old = defaults();
+        let new = applied(r#"
+            bind_addr = "0.0.0.0:64738"
+            storage_path = "/var/lib/stammer/state.json"
+            log_format = "json"
+            metrics_addr = "127.0.0.1:9100"
+            admin_addr = "127.0.0.1:8793"
+            admin_token = "sekrit"
+            event_socket = "unix:/run/stammer/events.sock"
+            event_queue_size = 16
+            max_users = 5
+            welcome_text = "hi"
+            session_queue_size = 8
+            allow_html = false
+            [tls]
+            cert_path = "cert.pem"
+            key_path = "key.pem"
+        "#).expect("valid config");
+        let reloaded = old.reloaded(new);
+        assert_eq!(reloaded.bind_addr, old.bind_addr);
+        assert_eq!(reloaded.tls, old.tls);
+        assert_eq!(reloaded.storage_path, old.storage_path);
+        assert_eq!(reloaded.log_format, old.log_format);
+        assert_eq!(reloaded.metrics_addr, old.metrics_addr);
+        assert_eq!(reloaded.admin_addr, old.admin_addr);
+        assert_eq!(reloaded.admin_token, old.admin_token);
+        assert_eq!(reloaded.event_socket, old.event_socket);
+        assert_eq!(reloaded.event_queue_size, old.event_queue_size);
+        // the rest applies right away
+        assert_eq!(reloaded.max_users, 5);
+        assert_eq!(reloaded.welcome_text, "hi");
+        assert_eq!(reloaded.session_queue_size, 8);
+        assert!(!reloaded.allow_html);
+    }
+}
@@ -0,0 +1,48 @@
+use mumble_protocol::control::msgs;
+use msgs::PermissionDenied_DenyType as DenyType;
+use msgs::Reject_RejectType as RejectType;
+use std::fmt;
+
+// errors which are the client's doing, and which the control task reports back to it
+// as a PermissionDenied instead of just logging them. they travel as anyhow errors
+#[derive(Debug)]
+pub struct Denied(pub msgs::PermissionDenied);
+
+impl Denied {
+    pub fn new(deny_type: DenyType, reason: impl Into<String>) -> Self {
+        let mut permission_denied = msgs::PermissionDenied::new();
+        permission_denied.set_field_type(deny_type);
+        permission_denied.set_reason(reason.into());
+        Self(permission_denied)
+    }
+
+    // a free-form denial, displayed as is by clients
+    pub fn text(reason: impl Into<String>) -> Self {
+        Self::new(DenyType::Text, reason)
+    }
+
+    // clients tell their users which permission they lack, and where
+    pub fn permission(session_id: u32, room_id: u32, permission: u32) -> Self {
+        let mut denied = Self::new(DenyType::Permission, format!("missing permission {:#x}", permission));
+        denied.0.set_session(session_id);
+        denied.0.set_channel_id(room_id);
+        denied.0.set_permission(permission);
+        denied
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "denied ({:?}): {}", self.0.get_field_type(), self.0.get_reason())
+    }
+}
+
+impl std::error::Error for Denied {}
+
+// unlike denials, rejects are the last thing clients hear before we hang up on them
+pub fn reject(reject_type: RejectType, reason: impl Into<String>) -> msgs::Reject {
+    let mut reject = msgs::Reject::new();
+    reject.set_field_type(reject_type);
+    reject.set_reason(reason.into());
+    reject
+}
@@ -0,0 +1,174 @@
+use anyhow::{Error,Result};
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime,Utc};
+use serde::Serialize;
+use hyper::Uri;
+use tokio::sync::broadcast;
+use log::{info,warn};
+use super::local_socket::LocalListener;
+use super::metrics::metrics;
+
+// what happens on the server that outside tooling (chat bridges, presence dashboards)
+// may want to react to. sessions are named along with their id, so that consumers
+// need not keep track of them
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Join{session: u32, name: String, user_id: Option<u32>, channel: u32},
+    Leave{session: u32, name: String},
+    ChannelSwitch{session: u32, name: String, from: u32, to: u32},
+    StartTalking{session: u32, name: String, channel: u32},
+    // messages sent through the admin api have no session
+    TextMessage{
+        session: Option<u32>,
+        name: Option<String>,
+        message: String,
+        sessions: Vec<u32>,
+        channels: Vec<u32>,
+        trees: Vec<u32>,
+    },
+}
+
+// an event as sinks get it, one json object with the time it happened at
+#[derive(Debug, Serialize)]
+pub struct EventRecord {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: Event,
+}
+
+// the control and routing tasks publish events on this bus, sinks subscribe to it.
+// every sink has a queue of its own: one which cannot keep up misses events, it
+// neither slows the others down nor the tasks publishing
+#[derive(Clone, Debug)]
+pub struct EventBus(broadcast::Sender<Arc<EventRecord>>);
+
+impl EventBus {
+    pub fn new(queue_size: usize) -> Self {
+        Self(broadcast::channel(queue_size).0)
+    }
+
+    pub fn publish(&self, event: Event) {
+        // fails if there is nobody listening, in which case there is nothing to do
+        let _ = self.0.send(Arc::new(EventRecord{time: Utc::now(), event}));
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<EventRecord>> {
+        self.0.subscribe()
+    }
+}
+
+// the next event for a sink, counting the ones it missed by being too slow. none once
+// the bus is gone, which means stammer stopped
+async fn next_event(events: &mut broadcast::Receiver<Arc<EventRecord>>, sink: &str) -> Option<Arc<EventRecord>> {
+    use tokio::sync::broadcast::RecvError;
+    loop {
+        match events.recv().await {
+            Ok(record) => return Some(record),
+            Err(RecvError::Lagged(missed)) => {
+                warn!("{} sink fell behind, dropped {} events", sink, missed);
+                metrics().events_dropped(sink, missed);
+            },
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+// webhooks are posted to over plain http, which only makes sense on this very host
+pub fn parse_webhook(url: &str) -> Result<Uri> {
+    let uri = url.parse::<Uri>().map_err(|err| Error::msg(format!("bad webhook url {:?}: {}", url, err)))?;
+    if uri.scheme_str() != Some("http") {
+        return Err(Error::msg(format!("webhook url {} is not an http:// url", uri)))
+    }
+    let local = match uri.host() {
+        Some("localhost") => true,
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    };
+    if !local {
+        return Err(Error::msg(format!("webhook url {} is not on a loopback address", uri)))
+    }
+    Ok(uri)
+}
+
+// every connection to the event socket gets the events from then on, one json object
+// per line. whatever connections send us is ignored
+pub async fn run_event_socket(listener: LocalListener, bus: EventBus) {
+    info!("serving events on {}", listener.describe());
+    let accepted = match listener {
+        LocalListener::Tcp(listener) => feed_connections(listener, bus).await,
+        LocalListener::Unix(listener) => feed_connections(listener, bus).await,
+    };
+    if let Err(err) = accepted {
+        warn!("event socket stopped: {}", err);
+    }
+}
+
+async fn feed_connections<L, C>(mut listener: L, bus: EventBus) -> std::io::Result<()>
+where
+    L: tokio::stream::Stream<Item = std::io::Result<C>> + Unpin,
+    C: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use tokio::stream::StreamExt;
+    while let Some(conn) = listener.next().await {
+        let mut conn = conn?;
+        let mut events = bus.subscribe();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            while let Some(record) = next_event(&mut events, "socket").await {
+                let mut line = serde_json::to_vec(&*record).expect("events serialize");
+                line.push(b'\n');
+                // the consumer hung up
+                if conn.write_all(&line).await.is_err() {
+                    break
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+// how long a webhook has to answer before we give up on the event
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// posts every event to the url, as json. events are posted one at a time, a failed post
+// is not retried: the event is dropped and we move on to the next
+pub async fn run_webhook(url: Uri, bus: EventBus) {
+    use hyper::{Body,Client,Request};
+    use tokio::time::timeout;
+    let client = Client::new();
+    // holding on to the bus would keep us from noticing stammer stopped
+    let mut events = bus.subscribe();
+    drop(bus);
+    info!("posting events to {}", url);
+
+    // a webhook which is down would fail every post, we only say so when it goes down
+    let mut failing = false;
+    while let Some(record) = next_event(&mut events, "webhook").await {
+        let request = Request::post(url.clone())
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&*record).expect("events serialize")))
+            .expect("valid request");
+        let posted = timeout(WEBHOOK_TIMEOUT, async {
+            let response = client.request(request).await.map_err(|err| err.to_string())?;
+            let status = response.status();
+            // read through, so that the connection can be reused
+            hyper::body::to_bytes(response.into_body()).await.map_err(|err| err.to_string())?;
+            if status.is_success() { Ok(()) } else { Err(format!("answered {}", status)) }
+        }).await.unwrap_or_else(|_| Err("timed out".to_owned()));
+
+        match posted {
+            Ok(()) if failing => { info!("webhook {} is back", url); failing = false },
+            Ok(()) => (),
+            Err(err) => {
+                metrics().events_dropped("webhook", 1);
+                if !failing {
+                    warn!("webhook {} failed, dropping events until it is back: {}", url, err);
+                    failing = true;
+                }
+            },
+        }
+    }
+}
@@ -1,50 +1,163 @@
 use std::sync::Arc;
 use tokio::sync::Notify;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener,UdpSocket};
+use tokio_rustls::TlsAcceptor;
+use std::path::PathBuf;
+use std::net::SocketAddr;
 use std::time::Duration;
-use anyhow::Result;
+use anyhow::{Error,Result};
 use log::info;
+use regex::Regex;
+use tokio::sync::mpsc::UnboundedReceiver as UReceiver;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct StammerConfig {
     pub bind_addr: String,
     pub session_timeout: Duration,
+    pub tls: Option<TlsConfig>,
+    pub welcome_text: String,
+    pub max_bandwidth: u32,
+    pub max_users: u32,
+    pub session_queue_size: usize,
+    pub max_saturation: Duration,
+    pub max_frame_len: usize,
+    pub storage_path: Option<PathBuf>,
+    pub superuser_password: Option<String>,
+    pub max_text_len: usize,
+    pub max_image_len: usize,
+    pub allow_html: bool,
+    pub min_client_version: u32,
+    // usernames must match the regex as a whole
+    pub username_regex: Regex,
+    pub max_username_len: usize,
+    pub log_levels: LogLevels,
+    pub log_format: LogFormat,
+    pub log_output: LogOutput,
+    // serves prometheus metrics over http, if set
+    pub metrics_addr: Option<SocketAddr>,
+    // serves the admin api, to those who have the token, if set
+    pub admin_addr: Option<LocalAddr>,
+    pub admin_token: Option<String>,
+    // where events (joins, leaves, channel switches, ...) are sent, if anywhere: to
+    // connections to a local socket, and to webhooks. each gets up to event_queue_size
+    // events behind before it misses some
+    pub event_socket: Option<LocalAddr>,
+    pub event_webhooks: Vec<hyper::Uri>,
+    pub event_queue_size: usize,
 }
 
+// the mumble protocol version we speak, 1.2.4
+const SERVER_VERSION: u32 = 1u32 << 16 | 2u32 << 8 | 4u32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[allow(clippy::too_many_arguments)] // the caller sets up what would otherwise be hidden away
 pub async fn run_stammer_task(
     stammer_cfg: StammerConfig,
     listener: TcpListener,
+    udp_socket: UdpSocket,
+    tls_acceptor: Option<TlsAcceptor>,
+    storage: Box<dyn Storage>,
+    stored_state: StoredState,
     stop: Arc<Notify>,
+    reload: UReceiver<StammerConfig>,
+    admin: Option<(LocalListener, String)>,
+    event_listener: Option<LocalListener>,
 ) {
     info!("starting stammer...");
 
     // control/routing task communications, read on for more context
     use tokio::sync::mpsc::unbounded_channel;
     let (control_sender, control_recver) = unbounded_channel();
-    let (routing_sender, routing_recver) = unbounded_channel();
+    let (routing_sender, routing_recver) = task_routing::routing_channel();
+    let (udp_sender, udp_recver) = unbounded_channel();
+    let (accept_sender, accept_recver) = unbounded_channel();
+    let (storage_sender, storage_recver) = unbounded_channel();
 
-    // the control task owns the routing table (connected sessions, room memberships, ...) it
-    // responds to multiple kinds of events (see ControlMessage). it sends/receives all
-    // control packets received/sent by individual session tasks. for example, upon room
-    // membership changes, it will:
+    // the control and routing tasks publish what happens on the server as events, which
+    // the sink tasks pass on to whoever subscribed. each sink task has its own queue, and
+    // misses events if it does not keep up: publishing never waits on a sink
+    use events::{EventBus,run_event_socket,run_webhook};
+    let event_bus = EventBus::new(stammer_cfg.event_queue_size);
+    if let Some(event_listener) = event_listener {
+        tokio::spawn(run_event_socket(event_listener, event_bus.clone()));
+    }
+    for url in &stammer_cfg.event_webhooks {
+        tokio::spawn(run_webhook(url.clone(), event_bus.clone()));
+    }
+
+    // the control task owns the routing table (connected sessions, room memberships, ...)
+    // initially restored from the stored state, which it then writes through to storage by
+    // way of the storage task. it responds to multiple kinds of events (see ControlMessage).
+    // it sends/receives all control packets received/sent by individual session tasks. for
+    // example, upon room membership changes, it will:
     //
     //  1. modify routing table upon receiving control packets by origin session
     //  2. send updated routing table to routing task for routing routing purposes
     //  3. declare new membership to all other sessions by sending them control packets
     use task_control::run_control_task;
-    let control_fut = run_control_task(control_recver, routing_sender.clone());
+    let bans = bans::BanList::restore(&stored_state.bans);
+    let control_fut = run_control_task(
+        stammer_cfg.clone(),
+        storage_sender,
+        stored_state,
+        control_recver,
+        control_sender.clone(),
+        accept_sender,
+        routing_sender.clone(),
+        udp_sender.clone(),
+        event_bus.clone(),
+    );
+
+    // the storage task writes the states handed over by the control task, off the control
+    // task since writes block until synced. it stops once the control task did, when the
+    // last state is written
+    use task_storage::run_storage_task;
+    let storage_fut = run_storage_task(storage, storage_recver);
 
     // the routing task routes voice packets from one source to N destinations
-    // using a view of the world regularly updated by the control task
+    // using a view of the world regularly updated by the control task. voice is
+    // sent over udp to sessions which have a working udp path, over tcp otherwise.
+    // it also delivers text messages, which session tasks made comply with the server's
+    // text policy beforehand
     use task_routing::run_routing_task;
-    let routing_fut = run_routing_task(routing_recver);
+    let routing_fut = run_routing_task(routing_recver, udp_sender, event_bus);
+
+    // the udp task owns the udp socket bound beside the tcp listener. it holds
+    // the crypt state of every authenticated session (handed over by the control
+    // task) with which it:
+    //
+    //  1. decrypts incoming voice datagrams, matching them to sessions by source
+    //     address or by trial decryption on first contact, and forwards them
+    //     to the routing task
+    //  2. encrypts and sends out voice packets handed over by the routing task
+    //  3. echoes udp pings, which is how clients find out udp works for them
+    //  4. answers the unencrypted pings of server browsers, with the user count
+    //     kept up to date by the control task
+    use task_udp::run_udp_task;
+    let udp_fut = run_udp_task(stammer_cfg.clone(), udp_socket, udp_recver, routing_sender.clone());
+
+    // the admin task serves the admin api, if enabled. it turns requests into messages to
+    // the control task, which carries them out like it would for a superuser's client
+    if let Some((admin_listener, admin_token)) = admin {
+        use tokio::spawn;
+        use admin::run_admin_task;
+        spawn(run_admin_task(admin_listener, admin_token, control_sender.clone()));
+    }
 
     // this task accepts new tcp connections and:
     //
-    //  1. assign them a unique session_id
-    //  2. kickstart the session task
-    //  3. shuts down the control task if it receives a stop notification
-    //  4. in case of a shutdown, wait for all session tasks to stop
+    //  1. turn away banned addresses, as per the ban list kept up to date by the control task
+    //  2. assign them a unique session_id, reusing those released by the control task
+    //  3. kickstart the session task (which terminates tls if we have an acceptor)
+    //  4. reap the session tasks as they stop
+    //  5. shuts down the control task if it receives a stop notification
+    //  6. in case of a shutdown, wait for all live session tasks to stop
+    //  7. hand reloaded configs over to the control task and all session tasks
     //
     // there is one session task per tcp connection. they terminate if
     // the connection terminates. they:
@@ -52,33 +165,170 @@ pub async fn run_stammer_task(
     //  1. perform version handshake with the client
     //  2. declare themselves to the control task
     //  3. forward any server-bound control packets to the control task
-    //  4. forward any tunneled routing packets to the routing task
+    //  4. forward any tunneled routing packets to the routing task, along with text
+    //     messages once sanitized as per the server's text policy
     //  5. forward any client-bound control packets to the client
     //
-    // if they encounter an error, session tasks will deregister from
-    // the control task themselves.
+    // however they stop, session tasks will deregister from the control
+    // task themselves, which then releases their session id.
     use task_accept::run_accept_task;
-    let accept_fut = run_accept_task(stammer_cfg, stop, listener, control_sender, routing_sender);
+    let accept_fut = run_accept_task(
+        stammer_cfg,
+        stop,
+        listener,
+        tls_acceptor,
+        bans,
+        reload,
+        accept_recver,
+        control_sender,
+        routing_sender,
+    );
 
     // server will run until caller notifies stop
     use tokio::join;
-    join!(control_fut, routing_fut, accept_fut);
+    // the control, routing and udp tasks log about the session their current message comes from
+    use logging::in_session;
+    join!(in_session(None, control_fut), in_session(None, routing_fut), in_session(None, udp_fut), storage_fut, accept_fut);
     info!("stammer has stopped");
 }
 
 impl StammerConfig {
     pub fn from_env() -> Result<Self> {
+        let stammer_cfg = Self::read_env()?;
+        stammer_cfg.validate()?;
+        Ok(stammer_cfg)
+    }
+
+    // the environment and the defaults, which the config file may still override, so
+    // they are validated once it did
+    fn read_env() -> Result<Self> {
         use std::env::var;
-        let session_timeout = var("STAMMER_SESSION_TIMEOUT_SECS").unwrap_or("30".to_owned());
+        // murmur's default
+        let username_regex = r"[-=\w\[\]\{\}\(\)\@\|\.]+";
         Ok(Self {
             bind_addr: var("STAMMER_BIND_ADDR").unwrap_or("localhost:8792".to_owned()),
-            session_timeout: Duration::from_secs(session_timeout.parse::<u64>()?),
+            session_timeout: Duration::from_secs(env_var("STAMMER_SESSION_TIMEOUT_SECS", "30", str::parse)?),
+            tls: TlsConfig::from_env()?,
+            welcome_text: var("STAMMER_WELCOME_TEXT").unwrap_or_default(),
+            max_bandwidth: env_var("STAMMER_MAX_BANDWIDTH", "72000", str::parse)?,
+            max_users: env_var("STAMMER_MAX_USERS", "100", str::parse)?,
+            session_queue_size: env_var("STAMMER_SESSION_QUEUE_SIZE", "64", str::parse)?,
+            max_saturation: Duration::from_secs(env_var("STAMMER_MAX_SATURATION_SECS", "5", str::parse)?),
+            max_frame_len: env_var("STAMMER_MAX_FRAME_LEN", "262144", str::parse)?,
+            storage_path: var("STAMMER_STORAGE_PATH").ok().map(PathBuf::from),
+            superuser_password: var("STAMMER_SUPERUSER_PASSWORD").ok(),
+            max_text_len: env_var("STAMMER_MAX_TEXT_LEN", "5000", str::parse)?,
+            max_image_len: env_var("STAMMER_MAX_IMAGE_LEN", "131072", str::parse)?,
+            allow_html: env_var("STAMMER_ALLOW_HTML", "true", str::parse)?,
+            min_client_version: env_var("STAMMER_MIN_CLIENT_VERSION", "1.2.0", parse_version)?,
+            username_regex: env_var("STAMMER_USERNAME_REGEX", username_regex, |regex| {
+                Regex::new(&format!("^(?:{})$", regex))
+            })?,
+            max_username_len: env_var("STAMMER_MAX_USERNAME_LEN", "128", str::parse)?,
+            log_levels: env_var("STAMMER_LOG_LEVEL", "info", str::parse)?,
+            log_format: env_var("STAMMER_LOG_FORMAT", "text", str::parse)?,
+            log_output: env_var("STAMMER_LOG_OUTPUT", "stderr", str::parse)?,
+            metrics_addr: optional_env_var("STAMMER_METRICS_ADDR", metrics::parse_metrics_addr)?,
+            admin_addr: optional_env_var("STAMMER_ADMIN_ADDR", str::parse)?,
+            admin_token: var("STAMMER_ADMIN_TOKEN").ok(),
+            event_socket: optional_env_var("STAMMER_EVENT_SOCKET", str::parse)?,
+            // comma-separated
+            event_webhooks: env_var("STAMMER_EVENT_WEBHOOKS", "", |urls| {
+                urls.split(',').map(str::trim).filter(|url| !url.is_empty()).map(events::parse_webhook).collect::<Result<_>>()
+            })?,
+            event_queue_size: env_var("STAMMER_EVENT_QUEUE_SIZE", "1024", str::parse)?,
         })
     }
+
+    // what would only fail later on, wherever the settings came from. errors name both
+    // the config file key and the environment variable
+    fn validate(&self) -> Result<()> {
+        if self.session_timeout.as_secs() == 0 {
+            return Err(Error::msg("session_timeout_secs (STAMMER_SESSION_TIMEOUT_SECS) cannot be 0"))
+        }
+        if self.session_queue_size == 0 {
+            return Err(Error::msg("session_queue_size (STAMMER_SESSION_QUEUE_SIZE) cannot be 0"))
+        }
+        if self.event_queue_size == 0 {
+            return Err(Error::msg("event_queue_size (STAMMER_EVENT_QUEUE_SIZE) cannot be 0"))
+        }
+        // the admin api is for those with the token, there is no serving it without one
+        match (&self.admin_addr, &self.admin_token) {
+            (Some(_), None) => Err(Error::msg("admin_addr (STAMMER_ADMIN_ADDR) is set but admin_token (STAMMER_ADMIN_TOKEN) is not")),
+            (Some(_), Some(token)) if token.is_empty() => Err(Error::msg("admin_token (STAMMER_ADMIN_TOKEN) cannot be empty")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// the environment variable, or the default if it is not set, parsed. errors name the
+// variable, like those of the config file name the key
+fn env_var<T, E: std::fmt::Display>(
+    name: &str,
+    default: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> Result<T> {
+    let value = std::env::var(name).unwrap_or_else(|_| default.to_owned());
+    parse(&value).map_err(|err| Error::msg(format!("{}: {}", name, err)))
+}
+
+fn optional_env_var<T, E: std::fmt::Display>(
+    name: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> Result<Option<T>> {
+    std::env::var(name).ok().map(|value| {
+        parse(&value).map_err(|err| Error::msg(format!("{}: {}", name, err)))
+    }).transpose()
+}
+
+// versions are configured as major.minor.patch, and travel as 0xMMMMmmpp
+fn parse_version(version: &str) -> Result<u32> {
+    let parts = version.split('.').map(str::parse::<u8>).collect::<std::result::Result<Vec<_>, _>>()?;
+    match parts.as_slice() {
+        [major, minor, patch] => Ok(u32::from(*major) << 16 | u32::from(*minor) << 8 | u32::from(*patch)),
+        _ => Err(Error::msg(format!("bad version {}, expected major.minor.patch", version))),
+    }
+}
+
+impl TlsConfig {
+    // tls is enabled only if both the certificate and key paths are provided
+    pub fn from_env() -> Result<Option<Self>> {
+        use std::env::var;
+        match (var("STAMMER_TLS_CERT_PATH"), var("STAMMER_TLS_KEY_PATH")) {
+            (Ok(cert_path), Ok(key_path)) => Ok(Some(Self {
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+            })),
+            (Err(_), Err(_)) => Ok(None),
+            _ => Err(Error::msg("STAMMER_TLS_CERT_PATH and STAMMER_TLS_KEY_PATH go together")),
+        }
+    }
 }
 
 mod task_accept;
 mod task_control;
 mod task_routing;
 mod task_session;
+mod task_udp;
+mod task_storage;
 mod routing_table;
+mod session_queue;
+mod denied;
+mod storage;
+mod registry;
+mod acl;
+mod bans;
+mod text_policy;
+mod config;
+mod logging;
+mod metrics;
+mod admin;
+mod local_socket;
+mod events;
+pub use storage::{Storage,StoredState,RoomRecord,UserRecord,PasswordHash,AclRecord,FileStorage,MemoryStorage};
+pub use acl::{AclEntry,Group};
+pub use bans::Ban;
+pub use logging::{LogLevels,LogFormat,LogOutput,setup_logging,reload_log_levels};
+pub use metrics::bind_metrics;
+pub use local_socket::{LocalAddr,LocalListener};
+mod tls;
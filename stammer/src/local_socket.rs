@@ -0,0 +1,60 @@
+use anyhow::{Error,Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::net::{TcpListener,UnixListener};
+
+// where local tooling reaches stammer (admin api, event stream): a unix socket, or a
+// tcp port on the loopback interface. nothing else, these are not meant for the outside
+#[derive(Clone, Debug, PartialEq)]
+pub enum LocalAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for LocalAddr {
+    type Err = Error;
+    fn from_str(addr: &str) -> Result<Self> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)))
+        }
+        let addr = addr.parse::<SocketAddr>().map_err(|err| {
+            Error::msg(format!("bad address {:?}, expected ip:port or unix:path: {}", addr, err))
+        })?;
+        if !addr.ip().is_loopback() {
+            return Err(Error::msg(format!("{} is not a loopback address", addr)))
+        }
+        Ok(Self::Tcp(addr))
+    }
+}
+
+pub enum LocalListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl LocalListener {
+    pub async fn bind(addr: &LocalAddr) -> Result<Self> {
+        match addr {
+            LocalAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            LocalAddr::Unix(path) => {
+                // a socket left behind by a previous run, which would keep us from binding
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            },
+        }
+    }
+
+    // for logging purposes
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map_or("?".to_owned(), |addr| addr.to_string()),
+            Self::Unix(listener) => listener.local_addr().ok().and_then(|addr| {
+                addr.as_pathname().map(|path| format!("unix:{}", path.display()))
+            }).unwrap_or_else(|| "unix:?".to_owned()),
+        }
+    }
+}
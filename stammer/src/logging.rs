@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::RwLock;
+pub use log_setup::{LogLevels,LogFormat,LogOutput,reload_log_levels};
+use log_setup::LogContext;
+
+// installs the logger for the whole process, once
+pub fn setup_logging(levels: &LogLevels, format: LogFormat, output: &LogOutput) -> Result<()> {
+    log_setup::setup_logging("stammer", levels, format, output, session_context)
+}
+
+// logs carry the session they are about, so one user can be followed through the tasks.
+// session tasks are about one session all along, the control, routing and udp tasks
+// about the one their current message comes from
+tokio::task_local! {
+    static SESSION: Cell<Option<u32>>;
+}
+
+// runs a task whose logs are about the given session, or about whichever it sets
+pub async fn in_session<F: Future>(session_id: Option<u32>, task: F) -> F::Output {
+    SESSION.scope(Cell::new(session_id), task).await
+}
+
+pub fn set_session(session_id: Option<u32>) {
+    // outside of in_session, there is no context to keep
+    let _ = SESSION.try_with(|session| session.set(session_id));
+}
+
+fn current_session() -> Option<u32> {
+    SESSION.try_with(Cell::get).ok().flatten()
+}
+
+// names of authenticated sessions, kept up to date by the control task
+static NAMES: RwLock<BTreeMap<u32, String>> = RwLock::new(BTreeMap::new());
+
+pub fn name_session(session_id: u32, name: &str) {
+    NAMES.write().expect("never poisoned").insert(session_id, name.to_owned());
+}
+
+pub fn forget_session(session_id: u32) {
+    NAMES.write().expect("never poisoned").remove(&session_id);
+}
+
+fn session_name(session_id: u32) -> Option<String> {
+    NAMES.read().expect("never poisoned").get(&session_id).cloned()
+}
+
+fn session_context() -> Option<LogContext> {
+    let session_id = current_session()?;
+    Some(match session_name(session_id) {
+        None => LogContext{label: format!("session {}", session_id), fields: vec![("session", session_id.into())]},
+        Some(name) => LogContext{
+            label: format!("session {} {}", session_id, name),
+            fields: vec![("session", session_id.into()), ("user", name.into())],
+        },
+    })
+}
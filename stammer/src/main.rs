@@ -5,28 +5,86 @@ use log::{warn,error,info};
 
 #[tokio::main]
 async fn main() {
-    if let Err(err) = setup_logging().await {
+    // the config comes first, it says how much to log
+    use stammer::StammerConfig;
+    let stammer_cfg = match StammerConfig::load() {
+        Ok(stammer_cfg) => stammer_cfg,
+        Err(err) => { eprintln!("failed to load config: {}", err); return },
+    };
+
+    if let Err(err) = setup_logging(&stammer_cfg).await {
         eprintln!("failed to setup logging: {}", err);
-    } else if let Err(err) = run_stammer().await {
+    } else if let Err(err) = run_stammer(stammer_cfg).await {
         error!("error while running stammer: {}", err);
     }
 }
 
-async fn run_stammer() -> Result<()> {
-    // load the stammer config and bind on tcp
-    use stammer::StammerConfig;
-    use tokio::net::TcpListener;
-    let stammer_cfg = StammerConfig::from_env()?;
+async fn run_stammer(stammer_cfg: stammer::StammerConfig) -> Result<()> {
+    // bind on tcp, then udp on the very same address
+    use tokio::net::{TcpListener,UdpSocket};
     let listener = TcpListener::bind(&stammer_cfg.bind_addr).await?;
+    let udp_socket = UdpSocket::bind(listener.local_addr()?).await?;
+
+    // load the tls certificate and key, standard mumble clients will not talk to us without it
+    let tls_acceptor = match &stammer_cfg.tls {
+        Some(tls_cfg) => Some(tls_cfg.load_acceptor()?),
+        None => { warn!("no tls certificate configured, clients will connect in cleartext"); None },
+    };
+
+    // serve metrics alongside, for the whole life of the process
+    if let Some(metrics_addr) = stammer_cfg.metrics_addr {
+        use tokio::spawn;
+        use stammer::bind_metrics;
+        spawn(bind_metrics(metrics_addr)?);
+    }
+
+    // the admin api, for those with the token, which the config made sure there is
+    let admin = match (&stammer_cfg.admin_addr, &stammer_cfg.admin_token) {
+        (Some(admin_addr), Some(token)) => {
+            use stammer::LocalListener;
+            Some((LocalListener::bind(admin_addr).await?, token.clone()))
+        },
+        _ => None,
+    };
+
+    // events go to whoever connects to the event socket, if any
+    let event_listener = match &stammer_cfg.event_socket {
+        Some(event_socket) => Some(stammer::LocalListener::bind(event_socket).await?),
+        None => None,
+    };
+
+    // restore what we stored before the last restart, if anything
+    let mut storage = stammer_cfg.open_storage()?;
+    let stored_state = storage.load()?;
+    stored_state.check()?;
 
     // enable stopping stammer using ctrl-c
     let stop = Arc::new(Notify::new());
     let cancel_fut = handle_ctrl_c(stop.clone());
 
+    // enable reloading the config file using SIGHUP, if we were started with one
+    use tokio::sync::mpsc::unbounded_channel;
+    let (reload_send, reload_recv) = unbounded_channel();
+    if let Some(path) = stammer::StammerConfig::file_path() {
+        use tokio::spawn;
+        spawn(handle_sighup(path, stammer_cfg.clone(), reload_send));
+    }
+
     // kickstart the stammer task
     use tokio::join;
     use stammer::run_stammer_task;
-    join!(cancel_fut, run_stammer_task(stammer_cfg, listener, stop));
+    join!(cancel_fut, run_stammer_task(
+        stammer_cfg,
+        listener,
+        udp_socket,
+        tls_acceptor,
+        storage,
+        stored_state,
+        stop,
+        reload_recv,
+        admin,
+        event_listener,
+    ));
 
     Ok(())
 }
@@ -41,11 +99,38 @@ async fn handle_ctrl_c(stop: Arc<Notify>) {
     }
 }
 
-async fn setup_logging() -> Result<()> {
-    use std::io::stderr;
-    use fern::Dispatch;
-    use log::LevelFilter;
-    Dispatch::new().level(LevelFilter::Trace).chain(stderr()).apply()?;
-    info!("logging to stderr setup successfully");
+// the operator edited the config file and wants the changes applied, without a restart
+async fn handle_sighup(
+    path: std::path::PathBuf,
+    mut stammer_cfg: stammer::StammerConfig,
+    reload_send: tokio::sync::mpsc::UnboundedSender<stammer::StammerConfig>,
+) {
+    use tokio::signal::unix::{signal,SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(err) => { warn!("failed to listen to SIGHUP signal, no config reload for us: {}", err); return },
+    };
+
+    while sighup.recv().await.is_some() {
+        info!("received SIGHUP signal, reloading {}", path.display());
+        match stammer::StammerConfig::from_file(&path) {
+            // a broken file is not worth stopping for, the operator can fix it and try again
+            Err(err) => error!("failed to reload config, keeping the current one: {}", err),
+            Ok(reloaded_cfg) => {
+                stammer_cfg = stammer_cfg.reloaded(reloaded_cfg);
+                stammer::reload_log_levels(&stammer_cfg.log_levels);
+                // stammer stopped, nothing left to reload
+                if reload_send.send(stammer_cfg.clone()).is_err() {
+                    break
+                }
+            },
+        }
+    }
+}
+
+async fn setup_logging(stammer_cfg: &stammer::StammerConfig) -> Result<()> {
+    use stammer::setup_logging;
+    setup_logging(&stammer_cfg.log_levels, stammer_cfg.log_format, &stammer_cfg.log_output)?;
+    info!("logging setup successfully");
     Ok(())
 }
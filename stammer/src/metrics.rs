@@ -0,0 +1,185 @@
+use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use prometheus::{Histogram,HistogramOpts,IntCounter,IntCounterVec,IntGauge,IntGaugeVec,Opts,Registry};
+use mumble_protocol::control::msgs::Reject_RejectType as RejectType;
+use log::{info,warn};
+
+// what stammer tells prometheus. the tasks update these as they go, the metrics
+// endpoint renders them whenever it gets scraped
+pub struct Metrics {
+    registry: Registry,
+    // tcp connections, whatever stage of the handshake they are at
+    pub connections: IntGauge,
+    // sessions past the version exchange, by whether they authenticated
+    pub sessions: IntGaugeVec,
+    pub channel_users: IntGaugeVec,
+    // serverbound control packets, handled by the control task
+    pub control_packets: IntCounter,
+    // voice packets routed, by the transport they reached us through
+    pub voice_packets: IntCounterVec,
+    // how many sessions each voice packet is routed to
+    pub voice_fanout: Histogram,
+    // messages waiting for the routing task
+    pub routing_queue: IntGauge,
+    pub dropped_packets: IntCounterVec,
+    // as counted by the crypt state of each session's udp path
+    pub crypt_packets: IntGaugeVec,
+    pub handshake_failures: IntCounterVec,
+    // events which did not make it to a sink, by sink
+    pub dropped_events: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self{
+            connections: IntGauge::new("stammer_connections", "open tcp connections").expect("valid metric"),
+            sessions: IntGaugeVec::new(
+                Opts::new("stammer_sessions", "sessions past the version exchange"),
+                &["state"],
+            ).expect("valid metric"),
+            channel_users: IntGaugeVec::new(
+                Opts::new("stammer_channel_users", "authenticated sessions per channel"),
+                &["channel_id", "channel"],
+            ).expect("valid metric"),
+            control_packets: IntCounter::new(
+                "stammer_control_packets_total", "control packets received from clients",
+            ).expect("valid metric"),
+            voice_packets: IntCounterVec::new(
+                Opts::new("stammer_voice_packets_total", "voice packets routed"),
+                &["transport"],
+            ).expect("valid metric"),
+            voice_fanout: Histogram::with_opts(
+                HistogramOpts::new("stammer_voice_fanout", "sessions a voice packet is routed to")
+                    .buckets(vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0]),
+            ).expect("valid metric"),
+            routing_queue: IntGauge::new(
+                "stammer_routing_queue_depth", "messages waiting for the routing task",
+            ).expect("valid metric"),
+            dropped_packets: IntCounterVec::new(
+                Opts::new("stammer_dropped_packets_total", "voice packets dropped"),
+                &["reason"],
+            ).expect("valid metric"),
+            crypt_packets: IntGaugeVec::new(
+                Opts::new("stammer_session_crypt_packets", "udp voice packets per session, as decrypted"),
+                &["session", "result"],
+            ).expect("valid metric"),
+            handshake_failures: IntCounterVec::new(
+                Opts::new("stammer_handshake_failures_total", "connections which did not make it to a session"),
+                &["reason"],
+            ).expect("valid metric"),
+            dropped_events: IntCounterVec::new(
+                Opts::new("stammer_dropped_events_total", "events dropped by sinks which could not keep up"),
+                &["sink"],
+            ).expect("valid metric"),
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.connections.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.sessions.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.channel_users.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.control_packets.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.voice_packets.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.voice_fanout.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.routing_queue.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.dropped_packets.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.crypt_packets.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.handshake_failures.clone())).expect("registered once");
+        metrics.registry.register(Box::new(metrics.dropped_events.clone())).expect("registered once");
+        metrics
+    }
+
+    pub fn handshake_failed(&self, reason: &str) {
+        self.handshake_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn packets_dropped(&self, reason: &str, count: u64) {
+        self.dropped_packets.with_label_values(&[reason]).inc_by(count);
+    }
+
+    pub fn events_dropped(&self, sink: &str, count: u64) {
+        self.dropped_events.with_label_values(&[sink]).inc_by(count);
+    }
+
+    pub fn set_crypt_stats(&self, session_id: u32, good: u32, late: u32, lost: u32) {
+        let session = session_id.to_string();
+        self.crypt_packets.with_label_values(&[&session, "good"]).set(good.into());
+        self.crypt_packets.with_label_values(&[&session, "late"]).set(late.into());
+        self.crypt_packets.with_label_values(&[&session, "lost"]).set(lost.into());
+    }
+
+    // session ids get reused, their stats do not
+    pub fn forget_crypt_stats(&self, session_id: u32) {
+        let session = session_id.to_string();
+        for result in &["good", "late", "lost"] {
+            let _ = self.crypt_packets.remove_label_values(&[&session, result]);
+        }
+    }
+
+    fn render(&self) -> String {
+        use prometheus::{Encoder,TextEncoder};
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).expect("text encoding cannot fail");
+        String::from_utf8(buf).expect("text encoding is utf8")
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+// how rejected authentications show up in handshake failures
+pub fn reject_reason(reject_type: RejectType) -> &'static str {
+    match reject_type {
+        RejectType::None => "rejected",
+        RejectType::WrongVersion => "wrong_version",
+        RejectType::InvalidUsername => "invalid_username",
+        RejectType::WrongUserPW => "wrong_user_password",
+        RejectType::WrongServerPW => "wrong_server_password",
+        RejectType::UsernameInUse => "username_in_use",
+        RejectType::ServerFull => "server_full",
+        RejectType::NoCertificate => "no_certificate",
+        RejectType::AuthenticatorFail => "authenticator_fail",
+    }
+}
+
+// prometheus scrapes over tcp, and the metrics are for the local one (or a local agent
+// forwarding them) only: a loopback ip:port
+pub fn parse_metrics_addr(addr: &str) -> Result<SocketAddr> {
+    use anyhow::Error;
+    use super::local_socket::LocalAddr;
+    match addr.parse::<LocalAddr>()? {
+        LocalAddr::Tcp(addr) => Ok(addr),
+        LocalAddr::Unix(_) => Err(Error::msg(format!("bad address {:?}, metrics are served on a loopback ip:port", addr))),
+    }
+}
+
+// serves GET /metrics on the given loopback address. binding happens right away so the
+// caller hears about a bad address, serving when awaited
+pub fn bind_metrics(addr: SocketAddr) -> Result<impl Future<Output = ()>> {
+    use std::convert::Infallible;
+    use hyper::{Body,Method,Request,Response,Server,StatusCode};
+    use hyper::service::{make_service_fn,service_fn};
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let response = match (req.method(), req.uri().path()) {
+                (&Method::GET, "/metrics") => Response::builder()
+                    .header("Content-Type", prometheus::TEXT_FORMAT)
+                    .body(Body::from(metrics().render())),
+                _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+            };
+            Ok::<_, Infallible>(response.expect("valid response"))
+        }))
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("serving metrics on http://{}/metrics", addr);
+    Ok(async move {
+        if let Err(err) = server.await {
+            warn!("metrics endpoint stopped: {}", err);
+        }
+    })
+}
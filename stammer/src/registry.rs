@@ -0,0 +1,117 @@
+use anyhow::{Error,Result};
+use std::collections::HashMap;
+use super::denied::Denied;
+use super::storage::{PasswordHash,UserRecord};
+use mumble_protocol::control::msgs::PermissionDenied_DenyType as DenyType;
+
+// like murmur, user 0 is the server administrator. its password is set from
+// our config, and it is the only user allowed to register other sessions
+pub const SUPERUSER_ID: u32 = 0;
+pub const SUPERUSER_NAME: &str = "SuperUser";
+
+// pbkdf2-hmac-sha384 parameters, the same murmur uses
+const PBKDF2_ITERATIONS: u32 = 16000;
+const PBKDF2_SALT_LEN: usize = 8;
+const PBKDF2_HASH_LEN: usize = 48;
+
+// the registered users, whose names are reserved to whoever holds their credentials.
+// names are compared case-insensitively, so that "Alice" cannot pass for "alice"
+#[derive(Debug, Default)]
+pub struct Registry {
+    users: HashMap<u32, UserRecord>,
+}
+
+impl Registry {
+    pub fn restore(records: &[UserRecord]) -> Self {
+        Self{users: records.iter().map(|record| (record.id, record.clone())).collect()}
+    }
+
+    pub fn records(&self) -> Vec<UserRecord> {
+        let mut records: Vec<UserRecord> = self.users.values().cloned().collect();
+        records.sort_unstable_by_key(|record| record.id);
+        records
+    }
+
+    pub fn find(&self, name: &str) -> Option<&UserRecord> {
+        let name = name.to_lowercase();
+        self.users.values().find(|user| user.name.to_lowercase() == name)
+    }
+
+    pub fn get(&self, user_id: u32) -> Option<&UserRecord> {
+        self.users.get(&user_id)
+    }
+
+    pub fn find_by_cert(&self, cert_hash: &str) -> Option<&UserRecord> {
+        self.users.values().find(|user| user.cert_hash.as_deref() == Some(cert_hash))
+    }
+
+    // returns the id of the newly registered user
+    pub fn register(&mut self, name: &str, password: Option<PasswordHash>, cert_hash: Option<String>) -> Result<u32> {
+        if self.find(name).is_some() || name.eq_ignore_ascii_case(SUPERUSER_NAME) {
+            let mut denied = Denied::new(DenyType::UserName, format!("{} is already registered", name));
+            denied.0.set_name(name.to_owned());
+            return Err(denied.into())
+        }
+        if let Some(user) = cert_hash.as_deref().and_then(|cert_hash| self.find_by_cert(cert_hash)) {
+            return Err(Denied::text(format!("this certificate is registered to {} already", user.name)).into())
+        }
+
+        let user_id = self.users.keys().max().map_or(SUPERUSER_ID + 1, |user_id| user_id + 1);
+        let user = UserRecord{id: user_id, name: name.to_owned(), password, cert_hash};
+        self.users.insert(user_id, user);
+        Ok(user_id)
+    }
+
+    pub fn set_superuser_password(&mut self, password: &str) -> Result<()> {
+        let password = Some(PasswordHash::new(password)?);
+        let user = UserRecord{id: SUPERUSER_ID, name: SUPERUSER_NAME.to_owned(), password, cert_hash: None};
+        self.users.insert(SUPERUSER_ID, user);
+        Ok(())
+    }
+
+    // users who registered with a password only pick up the certificate they log in with
+    // next, so that they do not need their password anymore. returns whether it did
+    pub fn adopt_cert(&mut self, user_id: u32, cert_hash: &str) -> bool {
+        // the superuser is the one account which always goes through its password
+        if user_id == SUPERUSER_ID || self.find_by_cert(cert_hash).is_some() {
+            return false
+        }
+        match self.users.get_mut(&user_id) {
+            Some(user) if user.cert_hash.is_none() => { user.cert_hash = Some(cert_hash.to_owned()); true },
+            _ => false,
+        }
+    }
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Result<Self> {
+        use openssl::rand::rand_bytes;
+        let mut salt = [0u8; PBKDF2_SALT_LEN];
+        rand_bytes(&mut salt)?;
+        let hash = pbkdf2(password, &salt, PBKDF2_ITERATIONS)?;
+        Ok(Self{salt: hex::encode(salt), hash: hex::encode(hash), iterations: PBKDF2_ITERATIONS})
+    }
+
+    // the iterations are stored along the hash, so that we can raise them without
+    // locking out users whose passwords were hashed with the previous count
+    pub fn verify(&self, password: &str) -> Result<bool> {
+        let salt = hex::decode(&self.salt)?;
+        let expected = hex::decode(&self.hash)?;
+        let hash = pbkdf2(password, &salt, self.iterations)?;
+        if hash.len() != expected.len() {
+            return Err(Error::msg("stored password hash has a bad length"))
+        }
+
+        // constant-time, so that timing does not tell how close a guess is
+        use openssl::memcmp;
+        Ok(memcmp::eq(&hash, &expected))
+    }
+}
+
+fn pbkdf2(password: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>> {
+    use openssl::hash::MessageDigest;
+    use openssl::pkcs5::pbkdf2_hmac;
+    let mut hash = vec![0u8; PBKDF2_HASH_LEN];
+    pbkdf2_hmac(password.as_bytes(), salt, iterations as usize, MessageDigest::sha384(), &mut hash)?;
+    Ok(hash)
+}
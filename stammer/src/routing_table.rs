@@ -1,46 +1,203 @@
 use anyhow::{Error,Result};
 use std::collections::{HashMap,HashSet};
-use tokio::sync::mpsc::UnboundedSender as USender;
+use std::net::IpAddr;
+use std::sync::Arc;
 use mumble_protocol::control::msgs;
-use mumble_protocol::control::ControlPacket;
-use mumble_protocol::voice::Clientbound;
 use log::debug;
+use super::denied::Denied;
+use super::session_queue::SessionSender;
+use super::storage::RoomRecord;
+use msgs::PermissionDenied_DenyType as DenyType;
 
 type SessionID = u32;
 type RoomID = u32;
+type Sender = SessionSender;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct RoutingTable {
     sessions: HashMap<SessionID, Session>,
+    // the room tree, which always has a root
     rooms: HashMap<RoomID, Room>,
 }
 
+// the root room is where sessions land, and cannot be removed
+pub const ROOT_ROOM_ID: RoomID = 0;
+
 #[derive(Clone, Debug)]
 struct Session {
+    name: String,
+    // set for registered users
+    user_id: Option<u32>,
+    // the hash of the client certificate, if any
+    cert_hash: Option<String>,
+    // where the client connects from, which is what bans are about
+    addr: IpAddr,
+    // access tokens, which make the session part of the matching #token acl groups
+    tokens: Vec<String>,
     room_id: RoomID,
     version: msgs::Version,
-    sender: USender<ControlPacket<Clientbound>>,
+    sender: Sender,
+    // whisper/shout targets registered through VoiceTarget, indexed by target id
+    voice_targets: HashMap<u8, VoiceTarget>,
+    // what the acl lets the session do with its voice and text messages, kept up to date
+    // by the control task. the room sets are shared, the control task sends the routing
+    // task a copy of the table upon every change
+    speak: bool,
+    whisper_rooms: Arc<HashSet<RoomID>>,
+    text_rooms: Arc<HashSet<RoomID>>,
+    flags: UserFlags,
+}
+
+// mute/deaf states of a session, set by its client (self_*) or by admins. clients duck
+// other audio when a priority speaker talks: they know who talks through the session id
+// of voice packets, and who is a priority speaker through the UserStates we broadcast
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserFlags {
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub mute: bool,
+    pub deaf: bool,
+    pub suppress: bool,
+    pub priority_speaker: bool,
+}
+
+impl UserFlags {
+    // whether the audio of the session should be dropped
+    fn silenced(&self) -> bool {
+        self.mute || self.self_mute || self.suppress
+    }
+
+    // whether the session should not be sent any audio
+    fn deafened(&self) -> bool {
+        self.deaf || self.self_deaf
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 struct Room {
+    name: String,
+    // only the root room has no parent
+    parent: Option<RoomID>,
+    description: String,
+    // ordering hint for clients displaying siblings
+    position: i32,
+    // 0 means unlimited
+    max_users: u32,
     members: HashSet<SessionID>,
+    // sub-rooms and linked rooms, which voice targets can reach through their parent.
+    // links go both ways: if a room links to another, that other room links back
+    children: HashSet<RoomID>,
+    links: HashSet<RoomID>,
+}
+
+// the recipients of a voice target, see:
+// https://mumble-protocol.readthedocs.io/en/latest/voice_data.html#whispering
+#[derive(Clone, Debug, Default)]
+struct VoiceTarget {
+    sessions: HashSet<SessionID>,
+    rooms: Vec<RoomTarget>,
+}
+
+#[derive(Clone, Debug)]
+struct RoomTarget {
+    room_id: RoomID,
+    links: bool,
+    children: bool,
+}
+
+// the target field of clientbound voice packets tells the listener how it was reached
+pub const TARGET_NORMAL: u8 = 0;
+pub const TARGET_SHOUT: u8 = 1;
+pub const TARGET_WHISPER: u8 = 2;
+// serverbound packets with this target are reflected to their speaker, for mic testing
+pub const TARGET_LOOPBACK: u8 = 31;
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        let root = Room{name: "Root".to_owned(), ..Room::default()};
+        let rooms = vec![(ROOT_ROOM_ID, root)].into_iter().collect();
+        Self{sessions: HashMap::new(), rooms}
+    }
 }
 
 impl RoutingTable {
+    // rebuild the room tree out of stored records, which have been checked at startup.
+    // no records at all means nothing was ever stored, and we start with a bare root
+    pub fn restore(records: &[RoomRecord]) -> Self {
+        if records.is_empty() {
+            return Self::default()
+        }
+
+        let mut rooms: HashMap<RoomID, Room> = records.iter().map(|record| (record.id, Room{
+            name: record.name.clone(),
+            parent: record.parent,
+            description: record.description.clone(),
+            position: record.position,
+            max_users: record.max_users,
+            ..Room::default()
+        })).collect();
+        for record in records {
+            if let Some(parent_id) = record.parent {
+                rooms.get_mut(&parent_id).expect("checked stored rooms").children.insert(record.id);
+            }
+            for link_id in &record.links {
+                rooms.get_mut(&record.id).expect("checked stored rooms").links.insert(*link_id);
+                rooms.get_mut(link_id).expect("checked stored rooms").links.insert(record.id);
+            }
+        }
+        Self{sessions: HashMap::new(), rooms}
+    }
+
+    // the room tree as stored, parents first and each link once
+    pub fn room_records(&self) -> Vec<RoomRecord> {
+        self.descendants(ROOT_ROOM_ID).into_iter().filter_map(|room_id| {
+            self.rooms.get(&room_id).map(|room| {
+                let mut links: Vec<RoomID> = room.links.iter().copied().filter(|link_id| *link_id < room_id).collect();
+                links.sort_unstable();
+                RoomRecord{
+                    id: room_id,
+                    parent: room.parent,
+                    name: room.name.clone(),
+                    description: room.description.clone(),
+                    position: room.position,
+                    max_users: room.max_users,
+                    links,
+                }
+            })
+        }).collect()
+    }
+
     pub fn holds_session(&self, session_id: SessionID) -> bool {
         self.sessions.contains_key(&session_id)
     }
 
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    #[allow(clippy::too_many_arguments)] // all of it describes the new session
     pub fn enroll_session(
         &mut self,
         session_id: SessionID,
+        name: String,
+        user_id: Option<u32>,
+        cert_hash: Option<String>,
+        addr: IpAddr,
         version: msgs::Version,
-        sender: USender<ControlPacket<Clientbound>>,
+        sender: Sender,
     ) {
-        let room_id = 0u32 as RoomID; // default room
-        self.sessions.insert(session_id, Session{room_id, version, sender});
-        self.rooms.entry(room_id).or_insert(Room::default()).members.insert(session_id);
+        let room_id = ROOT_ROOM_ID; // default room
+        let voice_targets = HashMap::new();
+        // until the control task says otherwise
+        let (speak, whisper_rooms, text_rooms) = (false, Arc::default(), Arc::default());
+        let tokens = vec![];
+        let flags = UserFlags::default();
+        let session = Session{
+            name, user_id, cert_hash, addr, tokens, room_id, version, sender, voice_targets, speak, whisper_rooms, text_rooms,
+            flags,
+        };
+        self.sessions.insert(session_id, session);
+        self.rooms.get_mut(&room_id).expect("root room always exists").members.insert(session_id);
     }
 
     pub fn expel_session(&mut self, session_id: SessionID) -> Result<()> {
@@ -51,7 +208,17 @@ impl RoutingTable {
         Ok(())
     }
 
-    pub fn _move_session(&mut self, session_id: SessionID, dest_room_id: RoomID) -> Result<()> {
+    pub fn move_session(&mut self, session_id: SessionID, dest_room_id: RoomID) -> Result<()> {
+        let dest_room = self.rooms.get(&dest_room_id).ok_or_else(|| {
+            Error::msg(format!("unknown room {}", dest_room_id))
+        })?;
+        if dest_room.max_users != 0 && dest_room.members.len() >= dest_room.max_users as usize {
+            let reason = format!("{} is full", dest_room.name);
+            let mut denied = Denied::new(DenyType::ChannelFull, reason);
+            denied.0.set_channel_id(dest_room_id);
+            return Err(denied.into())
+        }
+
         let session = self.sessions.get_mut(&session_id).ok_or_else(|| {
             Error::msg(format!("unknown session {}", session_id))
         })?;
@@ -63,21 +230,321 @@ impl RoutingTable {
         orig_room.members.remove(&session_id);
 
         debug!("session {} joined room {}", session_id, dest_room_id);
-        self.rooms.entry(dest_room_id).or_insert(Room::default()).members.insert(session_id);
+        self.rooms.get_mut(&dest_room_id).expect("checked above").members.insert(session_id);
         session.room_id = dest_room_id;
 
         Ok(())
     }
 
+    // create a room as described by a client's ChannelState, returns its id
+    pub fn create_room(&mut self, channel_state: &msgs::ChannelState) -> Result<RoomID> {
+        if !channel_state.has_parent() {
+            return Err(Denied::text("new channels need a parent").into())
+        }
+        let parent_id = channel_state.get_parent();
+        self.check_room_name(parent_id, channel_state.get_name(), None)?;
+
+        let room_id = self.rooms.keys().max().expect("root room always exists") + 1;
+        let room = Room{
+            name: channel_state.get_name().to_owned(),
+            parent: Some(parent_id),
+            description: channel_state.get_description().to_owned(),
+            position: channel_state.get_position(),
+            max_users: channel_state.get_max_users(),
+            ..Room::default()
+        };
+        self.rooms.insert(room_id, room);
+        self.rooms.get_mut(&parent_id).expect("checked above").children.insert(room_id);
+        debug!("created room {} under {}", room_id, parent_id);
+        Ok(room_id)
+    }
+
+    // apply the changes of a client's ChannelState to an existing room. everything is
+    // checked before anything is modified, so that a bad change leaves the room untouched
+    pub fn update_room(&mut self, room_id: RoomID, channel_state: &msgs::ChannelState) -> Result<()> {
+        let room = self.rooms.get(&room_id).ok_or_else(|| {
+            Error::msg(format!("unknown room {}", room_id))
+        })?;
+
+        // the new parent cannot be the room itself or one of its descendants
+        let parent_id = if channel_state.has_parent() { Some(channel_state.get_parent()) } else { room.parent };
+        if parent_id != room.parent {
+            let new_parent_id = parent_id.expect("has_parent checked");
+            if room_id == ROOT_ROOM_ID {
+                return Err(Denied::text("the root channel cannot be moved").into())
+            }
+            if self.descendants(room_id).contains(&new_parent_id) {
+                return Err(Denied::text("cannot move a channel into itself").into())
+            }
+        }
+        if channel_state.has_name() || parent_id != room.parent {
+            let name = if channel_state.has_name() { channel_state.get_name() } else { &room.name };
+            match parent_id {
+                Some(parent_id) => self.check_room_name(parent_id, name, Some(room_id))?,
+                None if name.is_empty() => return Err(Denied::new(DenyType::ChannelName, "empty name").into()),
+                None => (),
+            }
+        }
+
+        let links_add = channel_state.get_links_add();
+        let links_remove = channel_state.get_links_remove();
+        for link_id in links_add.iter().chain(links_remove) {
+            if *link_id == room_id || !self.rooms.contains_key(link_id) {
+                return Err(Error::msg(format!("bad link from room {} to {}", room_id, link_id)))
+            }
+        }
+
+        // all good, apply the changes
+        if parent_id != room.parent {
+            let orig_parent_id = room.parent.expect("root room does not move");
+            let new_parent_id = parent_id.expect("has_parent checked");
+            self.rooms.get_mut(&orig_parent_id).expect("bad tree").children.remove(&room_id);
+            self.rooms.get_mut(&new_parent_id).expect("checked above").children.insert(room_id);
+        }
+        for link_id in links_add {
+            self.rooms.get_mut(link_id).expect("checked above").links.insert(room_id);
+        }
+        for link_id in links_remove {
+            self.rooms.get_mut(link_id).expect("checked above").links.remove(&room_id);
+        }
+
+        let room = self.rooms.get_mut(&room_id).expect("checked above");
+        room.parent = parent_id;
+        room.links.extend(links_add);
+        for link_id in links_remove {
+            room.links.remove(link_id);
+        }
+        if channel_state.has_name() {
+            room.name = channel_state.get_name().to_owned();
+        }
+        if channel_state.has_description() {
+            room.description = channel_state.get_description().to_owned();
+        }
+        if channel_state.has_position() {
+            room.position = channel_state.get_position();
+        }
+        if channel_state.has_max_users() {
+            room.max_users = channel_state.get_max_users();
+        }
+        debug!("updated room {}", room_id);
+        Ok(())
+    }
+
+    // remove a room along with all of its sub-rooms. their members are moved to the parent
+    // of the removed room. returns the removed rooms (children first) and the moved sessions
+    pub fn remove_room(&mut self, room_id: RoomID) -> Result<(Vec<RoomID>, Vec<SessionID>)> {
+        let parent_id = match self.rooms.get(&room_id) {
+            None => return Err(Error::msg(format!("unknown room {}", room_id))),
+            Some(room) => match room.parent {
+                None => return Err(Denied::text("the root channel cannot be removed").into()),
+                Some(parent_id) => parent_id,
+            },
+        };
+        self.rooms.get_mut(&parent_id).expect("bad tree").children.remove(&room_id);
+
+        let mut removed = self.descendants(room_id);
+        removed.reverse();
+        let mut moved = vec![];
+        for removed_id in &removed {
+            let room = self.rooms.remove(removed_id).expect("bad tree");
+            for link_id in &room.links {
+                if let Some(linked_room) = self.rooms.get_mut(link_id) {
+                    linked_room.links.remove(removed_id);
+                }
+            }
+            for session_id in room.members {
+                self.sessions.get_mut(&session_id).expect("bad memberships").room_id = parent_id;
+                self.rooms.get_mut(&parent_id).expect("bad tree").members.insert(session_id);
+                moved.push(session_id);
+            }
+        }
+        debug!("removed rooms {:?}", removed);
+        Ok((removed, moved))
+    }
+
+    // sibling rooms must have distinct, non-empty names
+    fn check_room_name(&self, parent_id: RoomID, name: &str, room_id: Option<RoomID>) -> Result<()> {
+        let parent = self.rooms.get(&parent_id).ok_or_else(|| {
+            Error::msg(format!("unknown room {}", parent_id))
+        })?;
+        if name.is_empty() {
+            return Err(Denied::new(DenyType::ChannelName, "channel names cannot be empty").into())
+        }
+        let taken = parent.children.iter().filter(|child_id| Some(**child_id) != room_id).any(|child_id| {
+            matches!(self.rooms.get(child_id), Some(child) if child.name == name)
+        });
+        if taken {
+            let mut denied = Denied::new(DenyType::ChannelName, format!("{} already exists", name));
+            denied.0.set_name(name.to_owned());
+            return Err(denied.into())
+        }
+        Ok(())
+    }
+
+    // a room and all rooms below it, parents before children
+    fn descendants(&self, room_id: RoomID) -> Vec<RoomID> {
+        let mut descendants = vec![room_id];
+        let mut i = 0;
+        while let Some(room_id) = descendants.get(i) {
+            if let Some(room) = self.rooms.get(room_id) {
+                let mut children: Vec<RoomID> = room.children.iter().copied().collect();
+                children.sort_unstable();
+                descendants.extend(children);
+            }
+            i += 1;
+        }
+        descendants
+    }
+
+    pub fn room_state(&self, room_id: RoomID) -> Option<msgs::ChannelState> {
+        self.rooms.get(&room_id).map(|room| {
+            let mut channel_state = msgs::ChannelState::new();
+            channel_state.set_channel_id(room_id);
+            if let Some(parent_id) = room.parent {
+                channel_state.set_parent(parent_id);
+            }
+            channel_state.set_name(room.name.clone());
+            channel_state.set_description(room.description.clone());
+            channel_state.set_position(room.position);
+            channel_state.set_max_users(room.max_users);
+            channel_state.set_links(room.links.iter().copied().collect());
+            channel_state
+        })
+    }
+
+    // the whole tree, parents before children. links are sent in a second pass, once
+    // the client knows about all rooms
+    pub fn room_states(&self) -> Vec<msgs::ChannelState> {
+        let room_ids = self.descendants(ROOT_ROOM_ID);
+        let mut room_states: Vec<msgs::ChannelState> = room_ids.iter().filter_map(|room_id| {
+            self.room_state(*room_id).map(|mut channel_state| { channel_state.clear_links(); channel_state })
+        }).collect();
+        room_states.extend(room_ids.iter().filter_map(|room_id| self.rooms.get(room_id).map(|room| (room_id, room)))
+            .filter(|(_, room)| !room.links.is_empty()).map(|(room_id, room)| {
+                let mut channel_state = msgs::ChannelState::new();
+                channel_state.set_channel_id(*room_id);
+                channel_state.set_links(room.links.iter().copied().collect());
+                channel_state
+            })
+        );
+        room_states
+    }
+
+    // register (or clear, if it has no targets) one of the session's voice targets
+    pub fn set_voice_target(
+        &mut self,
+        session_id: SessionID,
+        voice_target: &msgs::VoiceTarget,
+    ) -> Result<()> {
+        let session = self.sessions.get_mut(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        })?;
+
+        // 0 is normal talking and 31 is loopback, only the ones in between are registrable
+        let target_id = match voice_target.get_id() {
+            target_id @ 1..=30 => target_id as u8,
+            target_id => return Err(Error::msg(format!("bad voice target id {}", target_id))),
+        };
+
+        if voice_target.get_targets().is_empty() {
+            debug!("session {} cleared voice target {}", session_id, target_id);
+            session.voice_targets.remove(&target_id);
+            return Ok(())
+        }
+
+        // TODO targets may also name acl groups, which the control task would need to resolve
+        let mut target = VoiceTarget::default();
+        for recipients in voice_target.get_targets() {
+            target.sessions.extend(recipients.get_session());
+            if recipients.has_channel_id() {
+                target.rooms.push(RoomTarget{
+                    room_id: recipients.get_channel_id(),
+                    links: recipients.get_links(),
+                    children: recipients.get_children(),
+                });
+            }
+        }
+        debug!("session {} registered voice target {}: {:?}", session_id, target_id, target);
+        session.voice_targets.insert(target_id, target);
+        Ok(())
+    }
+
+    // the recipients of a voice packet, along with the clientbound target they should see
     pub fn target_senders(
         &self,
         session_id: SessionID,
         target: u8,
-    ) -> Result<impl Iterator<Item=&USender<ControlPacket<Clientbound>>>> {
+    ) -> Result<Vec<(SessionID, u8, &Sender)>> {
+        let session = self.sessions.get(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        })?;
+        if target != TARGET_LOOPBACK && (!session.speak || session.flags.silenced()) {
+            return Ok(vec![])
+        }
+
         if target == 0u8 {
-            Ok(self.room_senders(self.room_id(session_id)?, Some(session_id)))
-        } else {
-            unimplemented!("non-zero targets not supported yet");
+            let room_senders = self.room_senders(self.room_id(session_id)?, Some(session_id));
+            return Ok(room_senders.filter(|(id, _)| self.hears(*id)).map(|(id, sender)| {
+                (id, TARGET_NORMAL, sender)
+            }).collect())
+        } else if target == TARGET_LOOPBACK {
+            // echo the audio back to its speaker only, as if it were normal talking
+            let sender = self.sender(session_id).ok_or_else(|| {
+                Error::msg(format!("unknown session {}", session_id))
+            })?;
+            return Ok(vec![(session_id, TARGET_NORMAL, sender)])
+        }
+
+        let voice_target = session.voice_targets.get(&target).ok_or_else(|| {
+            Error::msg(format!("session {} has no voice target {}", session_id, target))
+        })?;
+
+        // like murmur, listeners reached through a room are shouted at, even if
+        // they are also part of the target's explicit sessions. whispering needs
+        // permission in the rooms of the listeners
+        let mut room_ids = HashSet::new();
+        for room_target in &voice_target.rooms {
+            self.expand_room_target(room_target, &mut room_ids);
+        }
+        let shouted: HashSet<SessionID> = room_ids.intersection(&session.whisper_rooms).filter_map(|room_id| {
+            self.rooms.get(room_id)
+        }).flat_map(|room| room.members.iter().copied()).collect();
+        let whispered = voice_target.sessions.difference(&shouted).copied().filter(|id| {
+            matches!(self.sessions.get(id), Some(peer) if session.whisper_rooms.contains(&peer.room_id))
+        });
+
+        Ok(shouted.iter().map(|id| (*id, TARGET_SHOUT)).chain(
+            whispered.map(|id| (id, TARGET_WHISPER))
+        ).filter(|(id, _)| *id != session_id && self.hears(*id)).filter_map(|(id, target)| {
+            self.sender(id).map(|sender| (id, target, sender))
+        }).collect())
+    }
+
+    // deafened sessions are not sent any audio
+    fn hears(&self, session_id: SessionID) -> bool {
+        matches!(self.sessions.get(&session_id), Some(session) if !session.flags.deafened())
+    }
+
+    // the rooms reached by a room target: the room itself or all rooms it is
+    // linked to (transitively), and optionally all of its sub-rooms
+    fn expand_room_target(&self, room_target: &RoomTarget, room_ids: &mut HashSet<RoomID>) {
+        let mut pending = vec![room_target.room_id];
+        while let Some(room_id) = pending.pop() {
+            if room_ids.insert(room_id) && room_target.links {
+                if let Some(room) = self.rooms.get(&room_id) {
+                    pending.extend(room.links.iter().copied());
+                }
+            }
+        }
+
+        if room_target.children {
+            let mut pending = vec![room_target.room_id];
+            while let Some(room_id) = pending.pop() {
+                if let Some(room) = self.rooms.get(&room_id) {
+                    room_ids.extend(room.children.iter().copied());
+                    pending.extend(room.children.iter().copied());
+                }
+            }
         }
     }
 
@@ -85,27 +552,450 @@ impl RoutingTable {
         &self,
         room_id: RoomID,
         exclude: Option<SessionID>,
-    ) -> impl Iterator<Item=&USender<ControlPacket<Clientbound>>> {
-        // FIXME this will panic if somebody requests senders for a room that does not
-        // exist this is decided on the client side, so potential ddos from client
-        // could use std::iter::empty() but then we're returning != concrete types
-        //                       vvvvvvvv
-        self.rooms.get(&room_id).unwrap().members.iter().filter_map(move |session_id| {
+    ) -> impl Iterator<Item=(SessionID, &Sender)> {
+        // rooms are named by clients, so they may not exist (anymore)
+        let members = self.rooms.get(&room_id).into_iter().flat_map(|room| room.members.iter());
+        members.filter_map(move |session_id| {
             if exclude.is_some() && exclude.unwrap() == *session_id {
                 None
             } else {
-                self.sender(*session_id)
+                self.sender(*session_id).map(|sender| (*session_id, sender))
             }
         })
     }
 
-    pub fn sender(&self, session_id: SessionID) -> Option<&USender<ControlPacket<Clientbound>>> {
+    // the sessions in a room and in all rooms below it
+    pub fn tree_session_ids(&self, room_id: RoomID) -> Vec<SessionID> {
+        self.descendants(room_id).iter().filter_map(|room_id| self.rooms.get(room_id)).flat_map(|room| {
+            room.members.iter().copied()
+        }).collect()
+    }
+
+    // all recipients of a text message, each once. any references to sessions and rooms
+    // that have since then disappeared are just dropped silently
+    pub fn text_recipients(&self, text_message: &msgs::TextMessage) -> HashSet<SessionID> {
+        let mut recipient_ids: HashSet<SessionID> = text_message.get_session().iter().copied()
+            .filter(|session_id| self.holds_session(*session_id)).collect();
+        for room_id in text_message.get_channel_id() {
+            recipient_ids.extend(self.room_senders(*room_id, None).map(|(session_id, _)| session_id));
+        }
+        for tree_id in text_message.get_tree_id() {
+            recipient_ids.extend(self.tree_session_ids(*tree_id));
+        }
+        recipient_ids
+    }
+
+    // the first room a text message targets which the session may not send text messages
+    // to, if any. like murmur, sessions are targeted through the room they are in. like in
+    // text_recipients, sessions and rooms which have since then disappeared do not matter.
+    // a session the routing table does not know (yet) may not write anywhere
+    pub fn text_denied_room(&self, session_id: SessionID, text_message: &msgs::TextMessage) -> Option<RoomID> {
+        let text_rooms = match self.sessions.get(&session_id) {
+            Some(session) => &session.text_rooms,
+            None => return Some(ROOT_ROOM_ID),
+        };
+        let session_room_ids = text_message.get_session().iter().filter_map(|peer_id| {
+            self.sessions.get(peer_id).map(|peer| peer.room_id)
+        });
+        let room_ids = text_message.get_channel_id().iter().chain(text_message.get_tree_id()).copied()
+            .filter(|room_id| self.rooms.contains_key(room_id));
+        session_room_ids.chain(room_ids).find(|room_id| !text_rooms.contains(room_id))
+    }
+
+    pub fn sender(&self, session_id: SessionID) -> Option<&Sender> {
         self.sessions.get(&session_id).map(|s| &s.sender)
     }
 
-    fn room_id(&self, session_id: SessionID) -> Result<RoomID> {
+    pub fn all_senders(
+        &self,
+        exclude: Option<SessionID>,
+    ) -> impl Iterator<Item=&Sender> {
+        self.sessions.iter().filter_map(move |(session_id, session)| {
+            if exclude == Some(*session_id) {
+                None
+            } else {
+                Some(&session.sender)
+            }
+        })
+    }
+
+    pub fn user_state(&self, session_id: SessionID) -> Option<msgs::UserState> {
+        self.sessions.get(&session_id).map(|session| {
+            let mut user_state = msgs::UserState::new();
+            user_state.set_session(session_id);
+            user_state.set_name(session.name.clone());
+            if let Some(user_id) = session.user_id {
+                user_state.set_user_id(user_id);
+            }
+            user_state.set_channel_id(session.room_id);
+            // like murmur, only the flags which are set
+            let flags = &session.flags;
+            if flags.self_mute { user_state.set_self_mute(true) }
+            if flags.self_deaf { user_state.set_self_deaf(true) }
+            if flags.mute { user_state.set_mute(true) }
+            if flags.deaf { user_state.set_deaf(true) }
+            if flags.suppress { user_state.set_suppress(true) }
+            if flags.priority_speaker { user_state.set_priority_speaker(true) }
+            user_state
+        })
+    }
+
+    pub fn flags(&self, session_id: SessionID) -> Result<&UserFlags> {
+        self.sessions.get(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        }).map(|session| &session.flags)
+    }
+
+    pub fn set_flags(&mut self, session_id: SessionID, flags: UserFlags) -> Result<()> {
+        let session = self.sessions.get_mut(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        })?;
+        session.flags = flags;
+        Ok(())
+    }
+
+    pub fn tokens(&self, session_id: SessionID) -> &[String] {
+        self.sessions.get(&session_id).map(|session| session.tokens.as_slice()).unwrap_or_default()
+    }
+
+    pub fn set_tokens(&mut self, session_id: SessionID, tokens: Vec<String>) -> Result<()> {
+        let session = self.sessions.get_mut(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        })?;
+        session.tokens = tokens;
+        Ok(())
+    }
+
+    // whether the voice of the session gets routed to anyone at all
+    pub fn is_heard(&self, session_id: SessionID) -> bool {
+        matches!(self.sessions.get(&session_id), Some(session) if session.speak && !session.flags.silenced())
+    }
+
+    pub fn has_voice_targets(&self, session_id: SessionID) -> bool {
+        matches!(self.sessions.get(&session_id), Some(session) if !session.voice_targets.is_empty())
+    }
+
+    pub fn set_permissions(
+        &mut self,
+        session_id: SessionID,
+        speak: bool,
+        whisper_rooms: HashSet<RoomID>,
+        text_rooms: HashSet<RoomID>,
+    ) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.speak = speak;
+            session.whisper_rooms = Arc::new(whisper_rooms);
+            session.text_rooms = Arc::new(text_rooms);
+        }
+    }
+
+    pub fn room_ids(&self) -> impl Iterator<Item=RoomID> + '_ {
+        self.rooms.keys().copied()
+    }
+
+    pub fn room_parent(&self, room_id: RoomID) -> Option<RoomID> {
+        self.rooms.get(&room_id).and_then(|room| room.parent)
+    }
+
+    pub fn session_ids(&self) -> impl Iterator<Item=SessionID> + '_ {
+        self.sessions.keys().copied()
+    }
+
+    pub fn cert_hash(&self, session_id: SessionID) -> Option<&str> {
+        self.sessions.get(&session_id).and_then(|session| session.cert_hash.as_deref())
+    }
+
+    pub fn addr(&self, session_id: SessionID) -> Result<IpAddr> {
+        self.sessions.get(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        }).map(|session| session.addr)
+    }
+
+    // names are compared case-insensitively, like the registry does
+    pub fn holds_name(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.sessions.values().any(|session| session.name.to_lowercase() == name)
+    }
+
+    pub fn name(&self, session_id: SessionID) -> Result<&str> {
+        self.sessions.get(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        }).map(|session| session.name.as_str())
+    }
+
+    pub fn user_id(&self, session_id: SessionID) -> Option<u32> {
+        self.sessions.get(&session_id).and_then(|session| session.user_id)
+    }
+
+    pub fn set_user_id(&mut self, session_id: SessionID, user_id: u32) -> Result<()> {
+        let session = self.sessions.get_mut(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        })?;
+        session.user_id = Some(user_id);
+        Ok(())
+    }
+
+    pub fn room_id(&self, session_id: SessionID) -> Result<RoomID> {
         self.sessions.get(&session_id).ok_or_else(|| {
             Error::msg(format!("unknown session {}", session_id))
         }).map(|session| session.room_id)
     }
+
+    pub fn version(&self, session_id: SessionID) -> Result<&msgs::Version> {
+        self.sessions.get(&session_id).ok_or_else(|| {
+            Error::msg(format!("unknown session {}", session_id))
+        }).map(|session| &session.version)
+    }
+
+    // everything routing depends on, for operators to look at when voice does not go
+    // where they expect it to. ids are sorted so that dumps can be compared
+    pub fn dump(&self) -> serde_json::Value {
+        use serde_json::json;
+        let sorted = |ids: &HashSet<u32>| { let mut ids: Vec<u32> = ids.iter().copied().collect(); ids.sort_unstable(); ids };
+        let mut room_ids: Vec<RoomID> = self.rooms.keys().copied().collect();
+        room_ids.sort_unstable();
+        let rooms: Vec<_> = room_ids.iter().map(|room_id| {
+            let room = &self.rooms[room_id];
+            json!({
+                "id": room_id,
+                "name": room.name,
+                "parent": room.parent,
+                "max_users": room.max_users,
+                "members": sorted(&room.members),
+                "children": sorted(&room.children),
+                "links": sorted(&room.links),
+            })
+        }).collect();
+
+        let mut session_ids: Vec<SessionID> = self.sessions.keys().copied().collect();
+        session_ids.sort_unstable();
+        let sessions: Vec<_> = session_ids.iter().map(|session_id| {
+            let session = &self.sessions[session_id];
+            let mut target_ids: Vec<u8> = session.voice_targets.keys().copied().collect();
+            target_ids.sort_unstable();
+            let voice_targets: Vec<_> = target_ids.iter().map(|target_id| {
+                let target = &session.voice_targets[target_id];
+                json!({
+                    "id": target_id,
+                    "sessions": sorted(&target.sessions),
+                    "rooms": target.rooms.iter().map(|room| json!({
+                        "id": room.room_id, "links": room.links, "children": room.children,
+                    })).collect::<Vec<_>>(),
+                })
+            }).collect();
+            let flags = &session.flags;
+            json!({
+                "id": session_id,
+                "name": session.name,
+                "user_id": session.user_id,
+                "room": session.room_id,
+                "speak": session.speak,
+                "whisper_rooms": sorted(&session.whisper_rooms),
+                "text_rooms": sorted(&session.text_rooms),
+                "voice_targets": voice_targets,
+                "flags": {
+                    "self_mute": flags.self_mute,
+                    "self_deaf": flags.self_deaf,
+                    "mute": flags.mute,
+                    "deaf": flags.deaf,
+                    "suppress": flags.suppress,
+                    "priority_speaker": flags.priority_speaker,
+                },
+            })
+        }).collect();
+        json!({"rooms": rooms, "sessions": sessions})
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // rooms 0 > 1 > 2 > 3, and 0 > 4 which is linked to 3, with a session in each, numbered after it
+    fn sessions() -> RoutingTable {
+        let room = |id, parent, links| RoomRecord{
+            id, parent, name: format!("room {}", id), description: String::new(), position: 0, max_users: 0, links,
+        };
+        let mut rtbl = RoutingTable::restore(&[
+            room(0, None, vec![]), room(1, Some(0), vec![]), room(2, Some(1), vec![]), room(3, Some(2), vec![]), room(4, Some(0), vec![3]),
+        ]);
+        let (sender, _) = crate::session_queue::session_queue(1);
+        for session_id in 0..=4 {
+            let addr = "127.0.0.1".parse().expect("valid address");
+            rtbl.enroll_session(session_id, format!("user {}", session_id), None, None, addr, msgs::Version::new(), sender.clone());
+            rtbl.move_session(session_id, session_id).expect("room exists");
+        }
+        rtbl
+    }
+
+    fn text_message(sessions: &[SessionID], channels: &[RoomID], trees: &[RoomID]) -> msgs::TextMessage {
+        let mut text_message = msgs::TextMessage::new();
+        text_message.set_session(sessions.to_vec());
+        text_message.set_channel_id(channels.to_vec());
+        text_message.set_tree_id(trees.to_vec());
+        text_message.set_message("hello".to_owned());
+        text_message
+    }
+
+    // sessions to whisper to, and rooms to shout at along with their links/children
+    fn voice_target(id: u32, sessions: &[SessionID], rooms: &[(RoomID, bool, bool)]) -> msgs::VoiceTarget {
+        let mut targets = vec![];
+        if !sessions.is_empty() {
+            let mut target = msgs::VoiceTarget_Target::new();
+            target.set_session(sessions.to_vec());
+            targets.push(target);
+        }
+        for (room_id, links, children) in rooms {
+            let mut target = msgs::VoiceTarget_Target::new();
+            target.set_channel_id(*room_id);
+            target.set_links(*links);
+            target.set_children(*children);
+            targets.push(target);
+        }
+        let mut voice_target = msgs::VoiceTarget::new();
+        voice_target.set_id(id);
+        voice_target.set_targets(targets.into());
+        voice_target
+    }
+
+    // the session may speak, and whisper everywhere
+    fn speaker(rtbl: &mut RoutingTable, session_id: SessionID) {
+        let room_ids: HashSet<RoomID> = rtbl.room_ids().collect();
+        rtbl.set_permissions(session_id, true, room_ids.clone(), room_ids);
+    }
+
+    // who the voice reaches and how, sorted by session id
+    fn reached(rtbl: &RoutingTable, session_id: SessionID, target: u8) -> Vec<(SessionID, u8)> {
+        let mut reached: Vec<_> = rtbl.target_senders(session_id, target).expect("known target").into_iter()
+            .map(|(peer_id, peer_target, _)| (peer_id, peer_target)).collect();
+        reached.sort_unstable();
+        reached
+    }
+
+    #[test]
+    fn only_ids_1_to_30_are_voice_targets() {
+        let mut rtbl = sessions();
+        for id in &[0, 31, 32, 255] {
+            assert!(rtbl.set_voice_target(0, &voice_target(*id, &[1], &[])).is_err());
+        }
+        assert!(!rtbl.has_voice_targets(0));
+        for id in &[1, 30] {
+            rtbl.set_voice_target(0, &voice_target(*id, &[1], &[])).expect("registrable id");
+        }
+        assert!(rtbl.has_voice_targets(0));
+        // targets without recipients clear the voice target
+        rtbl.set_voice_target(0, &voice_target(1, &[], &[])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(30, &[], &[])).expect("registrable id");
+        assert!(!rtbl.has_voice_targets(0));
+        speaker(&mut rtbl, 0);
+        assert!(rtbl.target_senders(0, 1).is_err());
+    }
+
+    #[test]
+    fn sessions_are_whispered_to_and_rooms_shouted_at() {
+        let mut rtbl = sessions();
+        speaker(&mut rtbl, 0);
+        rtbl.set_voice_target(0, &voice_target(1, &[1, 2], &[])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(2, &[], &[(1, false, false)])).expect("registrable id");
+        assert_eq!(reached(&rtbl, 0, 1), vec![(1, TARGET_WHISPER), (2, TARGET_WHISPER)]);
+        assert_eq!(reached(&rtbl, 0, 2), vec![(1, TARGET_SHOUT)]);
+    }
+
+    #[test]
+    fn room_targets_expand_to_links_and_children() {
+        let mut rtbl = sessions();
+        speaker(&mut rtbl, 0);
+        rtbl.set_voice_target(0, &voice_target(1, &[], &[(4, false, false)])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(2, &[], &[(4, true, false)])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(3, &[], &[(1, false, true)])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(4, &[], &[(2, true, true)])).expect("registrable id");
+        assert_eq!(reached(&rtbl, 0, 1), vec![(4, TARGET_SHOUT)]);
+        assert_eq!(reached(&rtbl, 0, 2), vec![(3, TARGET_SHOUT), (4, TARGET_SHOUT)]);
+        assert_eq!(reached(&rtbl, 0, 3), vec![(1, TARGET_SHOUT), (2, TARGET_SHOUT), (3, TARGET_SHOUT)]);
+        // only the links of the targeted room count, not those of its children
+        assert_eq!(reached(&rtbl, 0, 4), vec![(2, TARGET_SHOUT), (3, TARGET_SHOUT)]);
+    }
+
+    #[test]
+    fn sessions_reached_twice_are_sent_voice_once() {
+        let mut rtbl = sessions();
+        speaker(&mut rtbl, 0);
+        // like murmur, those reached through a room are shouted at
+        rtbl.set_voice_target(0, &voice_target(1, &[1, 4], &[(1, false, false)])).expect("registrable id");
+        assert_eq!(reached(&rtbl, 0, 1), vec![(1, TARGET_SHOUT), (4, TARGET_WHISPER)]);
+    }
+
+    #[test]
+    fn speakers_do_not_hear_themselves() {
+        let mut rtbl = sessions();
+        speaker(&mut rtbl, 1);
+        rtbl.set_voice_target(1, &voice_target(1, &[1, 2], &[(1, false, true)])).expect("registrable id");
+        assert_eq!(reached(&rtbl, 1, 1), vec![(2, TARGET_SHOUT), (3, TARGET_SHOUT)]);
+        rtbl.move_session(0, 1).expect("room exists");
+        assert_eq!(reached(&rtbl, 1, 0), vec![(0, TARGET_NORMAL)]);
+    }
+
+    #[test]
+    fn silenced_speakers_and_deafened_listeners_are_left_out() {
+        let mut rtbl = sessions();
+        rtbl.set_voice_target(0, &voice_target(1, &[1, 2], &[(4, false, false)])).expect("registrable id");
+        // the acl does not let the session speak yet
+        assert_eq!(reached(&rtbl, 0, 1), vec![]);
+        speaker(&mut rtbl, 0);
+        assert_eq!(reached(&rtbl, 0, 1), vec![(1, TARGET_WHISPER), (2, TARGET_WHISPER), (4, TARGET_SHOUT)]);
+
+        rtbl.set_flags(2, UserFlags{self_deaf: true, ..UserFlags::default()}).expect("known session");
+        rtbl.set_flags(4, UserFlags{deaf: true, ..UserFlags::default()}).expect("known session");
+        assert_eq!(reached(&rtbl, 0, 1), vec![(1, TARGET_WHISPER)]);
+
+        let silenced = vec![
+            UserFlags{mute: true, ..UserFlags::default()},
+            UserFlags{self_mute: true, ..UserFlags::default()},
+            UserFlags{suppress: true, ..UserFlags::default()},
+        ];
+        for flags in silenced {
+            rtbl.set_flags(0, flags).expect("known session");
+            assert_eq!(reached(&rtbl, 0, 1), vec![]);
+        }
+    }
+
+    #[test]
+    fn whispers_only_reach_whisper_rooms() {
+        let mut rtbl = sessions();
+        rtbl.set_permissions(0, true, vec![0, 1, 4].into_iter().collect(), HashSet::new());
+        rtbl.set_voice_target(0, &voice_target(1, &[1, 2], &[])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(2, &[], &[(1, false, true)])).expect("registrable id");
+        rtbl.set_voice_target(0, &voice_target(3, &[], &[(4, true, false)])).expect("registrable id");
+        assert_eq!(reached(&rtbl, 0, 1), vec![(1, TARGET_WHISPER)]);
+        assert_eq!(reached(&rtbl, 0, 2), vec![(1, TARGET_SHOUT)]);
+        assert_eq!(reached(&rtbl, 0, 3), vec![(4, TARGET_SHOUT)]);
+    }
+
+    #[test]
+    fn unknown_senders_may_not_write() {
+        let mut rtbl = sessions();
+        assert_eq!(rtbl.text_denied_room(9, &text_message(&[1], &[], &[])), Some(ROOT_ROOM_ID));
+        assert_eq!(rtbl.text_denied_room(9, &text_message(&[], &[], &[])), Some(ROOT_ROOM_ID));
+        // neither may sessions the control task did not work out the permissions of yet
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[], &[1], &[])), Some(1));
+        rtbl.set_permissions(1, false, HashSet::new(), vec![1].into_iter().collect());
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[], &[1], &[])), None);
+    }
+
+    #[test]
+    fn text_rooms_apply_to_direct_recipients_and_rooms() {
+        let mut rtbl = sessions();
+        rtbl.set_permissions(1, false, HashSet::new(), vec![0, 1].into_iter().collect());
+        // sessions are checked through the room they are in
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[0], &[], &[])), None);
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[0, 2], &[], &[])), Some(2));
+        rtbl.move_session(2, 1).expect("room exists");
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[0, 2], &[], &[])), None);
+        // channels and trees are checked as such
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[], &[0, 1], &[1])), None);
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[], &[3], &[])), Some(3));
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[], &[], &[4])), Some(4));
+        // disappeared sessions and rooms are not
+        assert_eq!(rtbl.text_denied_room(1, &text_message(&[7], &[8], &[9])), None);
+    }
 }
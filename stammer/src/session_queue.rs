@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,AtomicU64,Ordering};
+use std::time::{Duration,Instant};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{
+    UnboundedSender as USender,
+    UnboundedReceiver as UReceiver,
+};
+use mumble_protocol::control::ControlPacket;
+use mumble_protocol::voice::Clientbound;
+use anyhow::{Error,Result};
+use super::metrics::metrics;
+
+// the outbound queue of a session, fed by the control/routing/udp tasks and drained by
+// the session task. a client on a stalled link must not make our memory grow forever:
+//
+//  - control packets are rare and must all be delivered, they are never dropped
+//  - voice packets come in at a steady rate and are only worth anything if fresh, so
+//    their queue is bounded and the stalest packets are dropped to make room
+//
+// a session whose voice queue does not drain for too long is considered saturated,
+// which the session task checks to disconnect clients that cannot keep up
+pub fn session_queue(capacity: usize) -> (SessionSender, SessionReceiver) {
+    use tokio::sync::mpsc::unbounded_channel;
+    let (control_send, control_recv) = unbounded_channel();
+    let voice = Arc::new(VoiceQueue{
+        capacity,
+        state: Mutex::new(VoiceState{
+            packets: VecDeque::with_capacity(capacity),
+            saturated_since: None,
+        }),
+        ready: Notify::new(),
+        dropped: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+    });
+    let sender = SessionSender{control: control_send, voice: voice.clone()};
+    let receiver = SessionReceiver{control: control_recv, voice};
+    (sender, receiver)
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionSender {
+    control: USender<ControlPacket<Clientbound>>,
+    voice: Arc<VoiceQueue>,
+}
+
+#[derive(Debug)]
+pub struct SessionReceiver {
+    control: UReceiver<ControlPacket<Clientbound>>,
+    voice: Arc<VoiceQueue>,
+}
+
+#[derive(Debug)]
+struct VoiceQueue {
+    capacity: usize,
+    state: Mutex<VoiceState>,
+    // wakes the session task up whenever packets are queued
+    ready: Notify,
+    // voice packets dropped so far, for the whole life of the session
+    dropped: AtomicU64,
+    // the session task is gone, nobody will drain this queue anymore
+    closed: AtomicBool,
+}
+
+#[derive(Debug)]
+struct VoiceState {
+    packets: VecDeque<ControlPacket<Clientbound>>,
+    // set when the queue overflows, reset once the session task drains it
+    saturated_since: Option<Instant>,
+}
+
+impl SessionSender {
+    // only fails if the session task is gone
+    pub fn send(&self, packet: ControlPacket<Clientbound>) -> Result<()> {
+        match packet {
+            ControlPacket::UDPTunnel(_) => self.send_voice(packet),
+            packet => self.control.send(packet).map_err(|_| Error::msg("session is gone")),
+        }
+    }
+
+    fn send_voice(&self, packet: ControlPacket<Clientbound>) -> Result<()> {
+        if self.voice.closed.load(Ordering::Relaxed) {
+            return Err(Error::msg("session is gone"))
+        }
+
+        let mut state = self.voice.state.lock().expect("poisoned voice queue");
+        if state.packets.len() >= self.voice.capacity {
+            state.packets.pop_front();
+            state.saturated_since.get_or_insert_with(Instant::now);
+            self.voice.dropped.fetch_add(1, Ordering::Relaxed);
+            metrics().packets_dropped("session_queue", 1);
+        }
+        state.packets.push_back(packet);
+        self.voice.ready.notify();
+        Ok(())
+    }
+
+    // how many voice packets the session missed so far, while it is live
+    pub fn dropped(&self) -> u64 {
+        self.voice.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl SessionReceiver {
+    // control packets go first. yields None once all senders are gone
+    pub async fn recv(&mut self) -> Option<ControlPacket<Clientbound>> {
+        loop {
+            use tokio::sync::mpsc::error::TryRecvError;
+            match self.control.try_recv() {
+                Ok(packet) => return Some(packet),
+                Err(TryRecvError::Closed) => return None,
+                Err(TryRecvError::Empty) => (),
+            }
+
+            if let Some(packet) = self.pop_voice() {
+                return Some(packet)
+            }
+
+            use tokio::select;
+            select! {
+                packet = self.control.recv() => return packet,
+                _ = self.voice.ready.notified() => continue,
+            }
+        }
+    }
+
+    fn pop_voice(&self) -> Option<ControlPacket<Clientbound>> {
+        let mut state = self.voice.state.lock().expect("poisoned voice queue");
+        let packet = state.packets.pop_front();
+        if state.packets.is_empty() {
+            state.saturated_since = None;
+        }
+        packet
+    }
+
+    // for how long the voice queue has been overflowing without being drained
+    pub fn saturated_for(&self) -> Option<Duration> {
+        let state = self.voice.state.lock().expect("poisoned voice queue");
+        state.saturated_since.map(|saturated_since| saturated_since.elapsed())
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.voice.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SessionReceiver {
+    fn drop(&mut self) {
+        self.voice.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mumble_protocol::control::msgs;
+    use mumble_protocol::voice::VoicePacket;
+
+    // voice packets are told apart by their timestamp
+    fn voice(timestamp: u64) -> ControlPacket<Clientbound> {
+        ControlPacket::UDPTunnel(Box::new(VoicePacket::Ping{timestamp}))
+    }
+
+    fn control(timestamp: u64) -> ControlPacket<Clientbound> {
+        let mut ping = msgs::Ping::new();
+        ping.set_timestamp(timestamp);
+        ping.into()
+    }
+
+    fn timestamp(packet: ControlPacket<Clientbound>) -> (&'static str, u64) {
+        match packet {
+            ControlPacket::UDPTunnel(voice) => match *voice {
+                VoicePacket::Ping{timestamp} => ("voice", timestamp),
+                _ => panic!("unexpected voice packet"),
+            },
+            ControlPacket::Ping(ping) => ("control", ping.get_timestamp()),
+            _ => panic!("unexpected control packet"),
+        }
+    }
+
+    #[tokio::test]
+    async fn overflows_drop_the_oldest_voice() {
+        let (sender, mut receiver) = session_queue(2);
+        for ts in 1..=5 {
+            sender.send(voice(ts)).expect("session is live");
+        }
+        assert_eq!(sender.dropped(), 3);
+        assert_eq!(receiver.dropped(), 3);
+        assert_eq!(timestamp(receiver.recv().await.expect("queued")), ("voice", 4));
+        assert_eq!(timestamp(receiver.recv().await.expect("queued")), ("voice", 5));
+    }
+
+    #[tokio::test]
+    async fn control_goes_before_voice() {
+        let (sender, mut receiver) = session_queue(4);
+        sender.send(voice(1)).expect("session is live");
+        sender.send(control(2)).expect("session is live");
+        sender.send(voice(3)).expect("session is live");
+        sender.send(control(4)).expect("session is live");
+        let mut packets = vec![];
+        for _ in 0..4 {
+            packets.push(timestamp(receiver.recv().await.expect("queued")));
+        }
+        assert_eq!(packets, vec![("control", 2), ("control", 4), ("voice", 1), ("voice", 3)]);
+        // control packets are never dropped
+        for ts in 0..10 {
+            sender.send(control(ts)).expect("session is live");
+        }
+        assert_eq!(sender.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn saturation_lasts_until_drained() {
+        let (sender, mut receiver) = session_queue(1);
+        sender.send(voice(1)).expect("session is live");
+        assert_eq!(receiver.saturated_for(), None);
+        sender.send(voice(2)).expect("session is live");
+        assert!(receiver.saturated_for().is_some());
+        assert_eq!(timestamp(receiver.recv().await.expect("queued")), ("voice", 2));
+        assert_eq!(receiver.saturated_for(), None);
+        // the count of dropped packets is for the whole life of the session
+        assert_eq!(receiver.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn sends_fail_once_the_receiver_is_gone() {
+        let (sender, receiver) = session_queue(1);
+        drop(receiver);
+        assert!(sender.send(voice(1)).is_err());
+        assert!(sender.send(control(1)).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use anyhow::{Error,Result};
+use serde::{Deserialize,Serialize};
+use std::collections::{HashMap,HashSet};
+use std::path::PathBuf;
+use log::{info,warn};
+use super::StammerConfig;
+use super::acl::{AclEntry,Group};
+use super::bans::Ban;
+
+// where stammer keeps what must survive a restart. the whole state is loaded once at
+// startup, then the storage task writes it through after every change the control task makes
+pub trait Storage: Send {
+    fn load(&mut self) -> Result<StoredState>;
+    fn save(&mut self, state: &StoredState) -> Result<()>;
+}
+
+// everything stammer remembers across restarts
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoredState {
+    pub rooms: Vec<RoomRecord>,
+    #[serde(default)]
+    pub users: Vec<UserRecord>,
+    #[serde(default)]
+    pub acls: Vec<AclRecord>,
+    #[serde(default)]
+    pub bans: Vec<Ban>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoomRecord {
+    pub id: u32,
+    // only the root room has no parent
+    pub parent: Option<u32>,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub position: i32,
+    #[serde(default)]
+    pub max_users: u32,
+    // links go both ways, but are only recorded once per pair
+    #[serde(default)]
+    pub links: Vec<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub id: u32,
+    pub name: String,
+    pub password: Option<PasswordHash>,
+    // the hash of the client certificate the user registered with
+    #[serde(default)]
+    pub cert_hash: Option<String>,
+}
+
+// the acl entries and groups of a room, rooms without any record
+// inherit from their parent. the root gets defaults without one
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclRecord {
+    pub room_id: u32,
+    pub inherit: bool,
+    #[serde(default)]
+    pub entries: Vec<AclEntry>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+}
+
+// pbkdf2 output, salt and hash are hex-encoded
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PasswordHash {
+    pub salt: String,
+    pub hash: String,
+    pub iterations: u32,
+}
+
+impl StammerConfig {
+    // the file backend if a path is configured, otherwise nothing survives a restart
+    pub fn open_storage(&self) -> Result<Box<dyn Storage>> {
+        match &self.storage_path {
+            Some(path) => Ok(Box::new(FileStorage::new(path.clone()))),
+            None => {
+                warn!("no storage path configured, server state will not survive restarts");
+                Ok(Box::new(MemoryStorage))
+            },
+        }
+    }
+}
+
+impl StoredState {
+    // stored state may have been edited by hand, or come from any storage backend. the
+    // control task builds its routing table out of it and relies on it being sound
+    pub fn check(&self) -> Result<()> {
+        let mut user_ids = HashSet::new();
+        let mut user_names = HashSet::new();
+        for user in &self.users {
+            if !user_ids.insert(user.id) || !user_names.insert(user.name.to_lowercase()) {
+                return Err(Error::msg(format!("user {} ({}) is stored twice", user.id, user.name)))
+            }
+        }
+        for ban in &self.bans {
+            ban.check()?;
+        }
+
+        // no rooms stored yet, the control task starts with a bare root
+        if self.rooms.is_empty() {
+            match self.acls.iter().find(|acl| acl.room_id != 0) {
+                Some(acl) => return Err(Error::msg(format!("acl stored for unknown room {}", acl.room_id))),
+                None => return Ok(()),
+            }
+        }
+
+        let mut parents = HashMap::new();
+        for room in &self.rooms {
+            if parents.insert(room.id, room.parent).is_some() {
+                return Err(Error::msg(format!("room {} is stored twice", room.id)))
+            }
+        }
+
+        let mut roots = parents.iter().filter(|(_, parent)| parent.is_none());
+        match (roots.next(), roots.next()) {
+            (Some((&0, _)), None) => (),
+            _ => return Err(Error::msg("stored rooms need exactly one root, with id 0")),
+        }
+
+        for room in &self.rooms {
+            // walking up from any room must reach the root, without going around in circles
+            let mut seen = HashSet::new();
+            let mut room_id = room.id;
+            while let Some(parent_id) = parents[&room_id] {
+                if !parents.contains_key(&parent_id) || !seen.insert(parent_id) {
+                    return Err(Error::msg(format!("room {} is not attached to the root", room.id)))
+                }
+                room_id = parent_id;
+            }
+
+            if let Some(link_id) = room.links.iter().find(|link_id| !parents.contains_key(link_id)) {
+                return Err(Error::msg(format!("room {} links to unknown room {}", room.id, link_id)))
+            }
+        }
+
+        let mut acl_room_ids = HashSet::new();
+        for acl in &self.acls {
+            if !parents.contains_key(&acl.room_id) || !acl_room_ids.insert(acl.room_id) {
+                return Err(Error::msg(format!("acl of room {} is stored twice or orphaned", acl.room_id)))
+            }
+        }
+        Ok(())
+    }
+}
+
+// keeps nothing, for servers which do not need to remember anything
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> Result<StoredState> {
+        Ok(StoredState::default())
+    }
+
+    fn save(&mut self, _state: &StoredState) -> Result<()> {
+        Ok(())
+    }
+}
+
+// the whole state as a single json document, rewritten on every change. that is
+// plenty for the amount of data a voice server holds, and easy to inspect/back up
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+// bumped whenever the format changes, so that older files can be migrated upon load
+const FILE_FORMAT_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct FileHeader {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileContents<S> {
+    version: u32,
+    #[serde(flatten)]
+    state: S,
+}
+
+impl FileStorage {
+    pub fn new(path: PathBuf) -> Self {
+        Self{path}
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<StoredState> {
+        use std::io::ErrorKind;
+        let contents = match std::fs::read(&self.path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!("no state stored at {} yet, starting afresh", self.path.display());
+                return Ok(StoredState::default())
+            },
+            Err(err) => return Err(Error::msg(format!("failed to read {}: {}", self.path.display(), err))),
+            Ok(contents) => contents,
+        };
+
+        let header: FileHeader = serde_json::from_slice(&contents)?;
+        match header.version {
+            FILE_FORMAT_VERSION => {
+                let file: FileContents<StoredState> = serde_json::from_slice(&contents)?;
+                info!("loaded state stored at {}", self.path.display());
+                Ok(file.state)
+            },
+            version => Err(Error::msg(format!(
+                "{} is in format version {}, we only know version {}",
+                self.path.display(), version, FILE_FORMAT_VERSION,
+            ))),
+        }
+    }
+
+    // write to a temporary file first, so that a crash mid-write never leaves us with a
+    // truncated state (rename replaces files atomically). the contents must be on disk
+    // before the rename is, and the rename itself only is once the directory is synced,
+    // otherwise a power loss may leave us with an empty file or the previous state
+    fn save(&mut self, state: &StoredState) -> Result<()> {
+        use std::fs::File;
+        use std::io::Write;
+        let file = FileContents{version: FILE_FORMAT_VERSION, state};
+        let contents = serde_json::to_vec_pretty(&file)?;
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&contents)?;
+        tmp_file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn room(id: u32, parent: Option<u32>, links: Vec<u32>) -> RoomRecord {
+        RoomRecord{id, parent, name: format!("room {}", id), description: String::new(), position: 0, max_users: 0, links}
+    }
+
+    fn user(id: u32, name: &str) -> UserRecord {
+        UserRecord{id, name: name.to_owned(), password: None, cert_hash: None}
+    }
+
+    fn acl(room_id: u32) -> AclRecord {
+        AclRecord{room_id, inherit: true, entries: vec![], groups: vec![]}
+    }
+
+    fn tree() -> StoredState {
+        StoredState{
+            rooms: vec![room(0, None, vec![]), room(1, Some(0), vec![2]), room(2, Some(1), vec![])],
+            users: vec![user(1, "alice"), user(2, "bob")],
+            acls: vec![acl(0), acl(2)],
+            bans: vec![],
+        }
+    }
+
+    #[test]
+    fn sound_states_pass() {
+        assert!(StoredState::default().check().is_ok());
+        assert!(tree().check().is_ok());
+        // the root's acl may be stored before any room is
+        let state = StoredState{acls: vec![acl(0)], ..StoredState::default()};
+        assert!(state.check().is_ok());
+    }
+
+    #[test]
+    fn users_are_stored_once() {
+        let mut state = tree();
+        state.users.push(user(1, "carol"));
+        assert!(state.check().is_err());
+        let mut state = tree();
+        state.users.push(user(3, "Alice"));
+        assert!(state.check().is_err());
+    }
+
+    #[test]
+    fn rooms_form_one_tree_under_root() {
+        let mut state = tree();
+        state.rooms.push(room(1, Some(0), vec![]));
+        assert!(state.check().is_err(), "room stored twice");
+
+        let mut state = tree();
+        state.rooms[0].parent = Some(2);
+        assert!(state.check().is_err(), "no root");
+
+        let mut state = tree();
+        state.rooms.push(room(3, None, vec![]));
+        assert!(state.check().is_err(), "two roots");
+
+        let state = StoredState{rooms: vec![room(1, None, vec![])], ..StoredState::default()};
+        assert!(state.check().is_err(), "root is not 0");
+
+        let mut state = tree();
+        state.rooms.push(room(3, Some(4), vec![]));
+        state.rooms.push(room(4, Some(3), vec![]));
+        assert!(state.check().is_err(), "cycle");
+
+        let mut state = tree();
+        state.rooms.push(room(3, Some(9), vec![]));
+        assert!(state.check().is_err(), "orphan");
+    }
+
+    #[test]
+    fn links_and_acls_point_to_stored_rooms() {
+        let mut state = tree();
+        state.rooms[2].links.push(9);
+        assert!(state.check().is_err());
+
+        let mut state = tree();
+        state.acls.push(acl(9));
+        assert!(state.check().is_err());
+
+        let mut state = tree();
+        state.acls.push(acl(2));
+        assert!(state.check().is_err());
+
+        let state = StoredState{acls: vec![acl(1)], ..StoredState::default()};
+        assert!(state.check().is_err());
+    }
+
+    #[test]
+    fn file_storage_round_trips() {
+        let path = std::env::temp_dir().join(format!("stammer-storage-test-{}.json", std::process::id()));
+        let mut storage = FileStorage::new(path.clone());
+        assert_eq!(storage.load().unwrap(), StoredState::default());
+        storage.save(&tree()).unwrap();
+        assert_eq!(storage.load().unwrap(), tree());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,25 +1,47 @@
 use std::sync::Arc;
+use std::collections::BTreeSet;
 use super::task_control::ControlMessage;
-use super::task_routing::RoutingMessage;
+use super::task_routing::RoutingSender;
 use tokio::net::TcpListener;
 use tokio::sync::Notify;
-use tokio::sync::mpsc::UnboundedSender as USender;
+use tokio::sync::mpsc::{
+    UnboundedSender as USender,
+    UnboundedReceiver as UReceiver,
+};
+use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::Framed;
 use mumble_protocol::control::ServerControlCodec;
-use log::{trace,info,error};
+use log::{trace,info,warn,error};
 use super::StammerConfig;
+use super::bans::BanList;
+use super::metrics::metrics;
 
+#[derive(Debug)]
+pub enum AcceptMessage {
+    ReleaseSession(u32),
+    UpdateBans(BanList),
+}
+
+#[allow(clippy::too_many_arguments)] // channels are handed to tasks one by one
 pub async fn run_accept_task(
     stammer_cfg: StammerConfig, // the global config of the stammer task
     stop: Arc<Notify>, // listened on for stop signal (ctrl-c)
     mut listener: TcpListener, // listened on for new tcp streams
+    tls_acceptor: Option<TlsAcceptor>, // terminates tls on new tcp streams, if configured
+    mut bans: BanList, // new tcp streams from banned addresses are dropped right away
+    mut reload: UReceiver<StammerConfig>, // configs reloaded by the operator
+    mut accept_recv: UReceiver<AcceptMessage>, // session ids given back by the control task
     control_send: USender<ControlMessage>, // hand to session tasks + notify about new sessions
-    routing_send: USender<RoutingMessage>, // hand to session tasks
+    routing_send: RoutingSender, // hand to session tasks
 ) {
     trace!("accept task started");
-    // list of session tasks for future join
-    // FIXME will keep growing with sessions. unclear how to purge closed sessions atm
-    let mut sessions = vec![];
+    let mut session_ids = SessionIDs::default();
+    // session tasks start with the latest config, and pick up reloaded ones as they run
+    use tokio::sync::watch;
+    let (config_send, config_recv) = watch::channel(stammer_cfg);
+    // live session tasks, for future join. they are reaped as they stop
+    use futures::stream::FuturesUnordered;
+    let mut sessions = FuturesUnordered::new();
 
     loop {
         use tokio::select;
@@ -27,6 +49,28 @@ pub async fn run_accept_task(
         select! {
             // if any other task fails, program shutdown
             _ = stop.notified() => break,
+
+            // session tasks stop on their own when their client leaves
+            _ = sessions.next(), if !sessions.is_empty() => {
+                metrics().connections.set(sessions.len() as i64);
+            },
+
+            // the operator reloaded the config, everyone needs to know
+            Some(stammer_cfg) = reload.next() => {
+                info!("config reloaded, handing it over to control and session tasks");
+                config_send.broadcast(stammer_cfg.clone()).expect("we hold a receiver");
+                let msg = ControlMessage::Reload(Box::new(stammer_cfg));
+                control_send.send(msg).expect("control cannot be closed yet");
+            },
+
+            // the control task is done with a session, its id can be reused
+            msg = accept_recv.next() => match msg {
+                None => unreachable!("control task stops after the accept task"),
+                Some(AcceptMessage::ReleaseSession(session_id)) => session_ids.release(session_id),
+                // the control task edited the ban list
+                Some(AcceptMessage::UpdateBans(ban_list)) => bans = ban_list,
+            },
+
             // triggered whenever a client connects
             tcp_stream = listener.next() => match tcp_stream {
                 None => unreachable!("bound listener stream never ends"),
@@ -37,23 +81,55 @@ pub async fn run_accept_task(
                     break
                 },
                 Some(Ok(tcp_stream)) => {
-                    // select unique id for the new session
-                    let session_id = sessions.len() as u32;
-                    info!("received new connection, assigning session id {}", session_id);
+                    // the peer address is needed to match udp datagrams to the session
+                    let addr = match tcp_stream.peer_addr() {
+                        Ok(addr) => addr,
+                        Err(err) => { warn!("dropping connection without peer: {}", err); continue },
+                    };
+                    if let Some(ban) = bans.find(addr.ip(), None) {
+                        info!("dropping connection from {}: banned ({})", addr, ban.reason);
+                        metrics().handshake_failed("banned");
+                        continue
+                    }
 
-                    // wrap tcp stream in a mumble protocol framed codec
-                    let codec_stream = Framed::new(tcp_stream, ServerControlCodec::new());
+                    // select unique id for the new session
+                    let session_id = match session_ids.allocate() {
+                        Some(session_id) => session_id,
+                        None => { warn!("dropping connection from {}: out of session ids", addr); continue },
+                    };
+                    info!("received new connection from {}, assigning session id {}", addr, session_id);
 
                     // kickoff the session task
                     use tokio::spawn;
-                    use super::task_session::run_session_task;
-                    sessions.push(spawn(run_session_task(
-                        stammer_cfg.clone(),
-                        session_id, // identify session when sending to control/routing
-                        codec_stream, // codec-ed connection to the client
-                        control_send.clone(), // any control packets send there
-                        routing_send.clone(), // voice packets will be sent there
-                    )));
+                    use super::task_session::{run_session_task,run_tls_session_task};
+                    // everything the session task logs is about its session
+                    use super::logging::in_session;
+                    let session_task = match tls_acceptor.clone() {
+                        // the tls handshake is performed by the session task itself
+                        Some(tls_acceptor) => spawn(in_session(Some(session_id), run_tls_session_task(
+                            config_recv.clone(), // the config, as reloaded over time
+                            session_id, // identify session when sending to control/routing
+                            addr, // the client's address
+                            tls_acceptor, // wraps the tcp stream in tls
+                            tcp_stream, // raw connection to the client
+                            control_send.clone(), // any control packets send there
+                            routing_send.clone(), // voice packets will be sent there
+                        ))),
+                        // no tls configured, wrap tcp stream in a mumble protocol framed codec
+                        None => spawn(in_session(Some(session_id), run_session_task(
+                            config_recv.clone(), // the config, as reloaded over time
+                            session_id, // identify session when sending to control/routing
+                            addr, // the client's address
+                            None, // no tls, no client certificate
+                            Framed::new(tcp_stream, ServerControlCodec::with_max_frame_len(
+                                config_recv.borrow().max_frame_len, // oversized packets are refused
+                            )),
+                            control_send.clone(), // any control packets send there
+                            routing_send.clone(), // voice packets will be sent there
+                        ))),
+                    };
+                    sessions.push(session_task);
+                    metrics().connections.set(sessions.len() as i64);
                 },
             },
         }
@@ -66,9 +142,38 @@ pub async fn run_accept_task(
         trace!("no session tasks to wait on (#SAD!)");
     } else {
         trace!("waiting for all {} session tasks to stop...", sessions.len());
-        use futures::future::join_all;
-        join_all(sessions).await;
+        use tokio::stream::StreamExt;
+        while sessions.next().await.is_some() {}
     }
 
     trace!("accept task stopped")
 }
+
+// hands out session ids, reusing the lowest released ones first like murmur does. an id is
+// only released once the control task forgot about its session, which guarantees that the
+// control, routing and udp tasks never mistake a new session for an old one
+#[derive(Default)]
+struct SessionIDs {
+    // ids above this one were never handed out
+    next: u32,
+    released: BTreeSet<u32>,
+}
+
+impl SessionIDs {
+    fn allocate(&mut self) -> Option<u32> {
+        if let Some(session_id) = self.released.iter().next().copied() {
+            self.released.remove(&session_id);
+            Some(session_id)
+        } else {
+            let session_id = self.next;
+            self.next = self.next.checked_add(1)?;
+            Some(session_id)
+        }
+    }
+
+    fn release(&mut self, session_id: u32) {
+        if session_id >= self.next || !self.released.insert(session_id) {
+            warn!("released session id {} was not in use", session_id);
+        }
+    }
+}
@@ -1,69 +1,172 @@
-use super::routing_table::RoutingTable;
+use super::routing_table::{RoutingTable,ROOT_ROOM_ID};
 use tokio::sync::mpsc::{
     UnboundedSender as USender,
     UnboundedReceiver as UReceiver,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet};
+use std::net::{IpAddr,SocketAddr};
 use mumble_protocol::{
     control::{ControlPacket,msgs},
     voice::{Serverbound,Clientbound},
 };
 use log::{trace,warn,info,debug};
+use super::StammerConfig;
+use super::logging;
+use super::metrics::{metrics,reject_reason};
+use super::session_queue::SessionSender;
+use super::denied::{Denied,reject};
+use super::storage::{PasswordHash,StoredState};
+use super::registry::{Registry,SUPERUSER_ID,SUPERUSER_NAME};
+use super::acl::{self,Acl,Permissions};
+use super::bans::{Ban,BanList};
+use super::admin::{AdminRequest,AdminReply};
+use super::events::{Event,EventBus};
+use msgs::PermissionDenied_DenyType as DenyType;
+use msgs::Reject_RejectType as RejectType;
 
 #[derive(Debug)]
 pub enum ControlMessage {
     Packet(u32, ControlPacket<Serverbound>),
     AddSession(u32, UnAuthSession),
     RemoveSession(u32),
+    Reload(Box<StammerConfig>),
+    Admin(AdminRequest, AdminReply),
+    PasswordChecked(u32, bool),
+    PasswordHashed(Registration, Result<PasswordHash>),
 
     Shutdown,
 }
 
+impl ControlMessage {
+    // the session this message comes from, for logging purposes
+    fn session_id(&self) -> Option<u32> {
+        match self {
+            Self::Packet(session_id, _) | Self::AddSession(session_id, _) | Self::RemoveSession(session_id) => Some(*session_id),
+            Self::PasswordChecked(session_id, _) => Some(*session_id),
+            Self::PasswordHashed(registration, _) => Some(registration.actor_id),
+            Self::Reload(_) | Self::Admin(..) | Self::Shutdown => None,
+        }
+    }
+}
+
 // incoming sessions sent by the accept task to the control task. those are not
 // authenticated yet. once they are, they will be enrolled in the routing table
 #[derive(Debug)]
 pub struct UnAuthSession {
+    pub addr: SocketAddr,
     pub version: msgs::Version,
-    pub send: USender<ControlPacket<Clientbound>>,
+    pub cert_hash: Option<String>,
+    pub send: SessionSender,
 }
 
-use super::task_routing::RoutingMessage;
+// sessions which authenticated as a registered user wait here while their password is
+// checked, off the control task: pbkdf2 is costly by design
+struct PendingLogin {
+    name: String,
+    user_id: Option<u32>,
+    tokens: Vec<String>,
+    unauth_session: UnAuthSession,
+}
+
+// a registration waiting on the password of the session to be hashed, off the control
+// task as well. the session may leave meanwhile, and its id be reused by another
+#[derive(Debug)]
+pub struct Registration {
+    session_id: u32,
+    actor_id: u32,
+    name: String,
+    password: String,
+}
+
+use super::task_accept::AcceptMessage;
+use super::task_routing::{RoutingMessage,RoutingSender};
+use super::task_udp::{UdpMessage,UdpSession};
+#[allow(clippy::too_many_arguments)] // the control task talks to every other task
 pub async fn run_control_task(
+    stammer_cfg: StammerConfig,
+    storage_send: USender<StoredState>,
+    stored_state: StoredState,
     mut control_recv: UReceiver<ControlMessage>,
-    mut routing_send: USender<RoutingMessage>,
+    control_send: USender<ControlMessage>,
+    accept_send: USender<AcceptMessage>,
+    routing_send: RoutingSender,
+    udp_send: USender<UdpMessage>,
+    events: EventBus,
 ) {
     trace!("control task started");
-    // where sessions are stored before they authenticate
-    let mut unauth: HashMap<u32, UnAuthSession> = HashMap::new();
-    // once authenticated, sessions are routable
-    let mut rtbl = RoutingTable::default();
+    let mut ctrl = Control{
+        stammer_cfg,
+        unauth: HashMap::new(),
+        logins: HashMap::new(),
+        rtbl: RoutingTable::restore(&stored_state.rooms),
+        registry: Registry::restore(&stored_state.users),
+        acl: Acl::restore(&stored_state.acls),
+        bans: BanList::restore(&stored_state.bans),
+        passwords: HashMap::new(),
+        storage_send,
+        control_send,
+        accept_send,
+        routing_send,
+        udp_send,
+        events,
+    };
+
+    // the superuser password may change between restarts, the config has the last word
+    ctrl.set_superuser_password();
 
     use tokio::stream::StreamExt;
     while let Some(msg) = control_recv.next().await {
+        logging::set_session(msg.session_id());
         match msg {
             // sent by session tasks upon receiving a control packet from client
             ControlMessage::Packet(id, packet) => {
-                let res = handle_packet(id, packet, &mut unauth, &mut routing_send, &mut rtbl);
-                if let Err(err) = res {
-                    warn!("packet handling: {}", err);
+                metrics().control_packets.inc();
+                if let Err(err) = ctrl.handle_packet(id, packet) {
+                    // denials are for the client to know about, the rest is our business
+                    match err.downcast::<Denied>() {
+                        Ok(denied) => ctrl.deny(id, denied),
+                        Err(err) => warn!("packet handling: {}", err),
+                    }
                 }
             },
 
             // sent by session tasks after proper version handshake
             ControlMessage::AddSession(session_id, unauth_session) => {
-                unauth.insert(session_id, unauth_session);
+                ctrl.unauth.insert(session_id, unauth_session);
             },
 
-            // sent by session tasks whenever they die ungracefully
+            // sent by session tasks as their very last message, however they stop
             ControlMessage::RemoveSession(session_id) => {
-                if unauth.remove(&session_id).is_none() {
-                    if let Err(err) = rtbl.expel_session(session_id) {
-                        warn!("failed to expel session {}: {}", session_id, err);
-                    } else {
-                        info!("expelled session {} from routing table", session_id);
-                        // forward routing table to routing task
-                        let msg = RoutingMessage::Update(rtbl.clone());
-                        routing_send.send(msg).expect("routing cannot be closed yet");
+                ctrl.remove_session(session_id);
+                logging::forget_session(session_id);
+                // the accept task may stop before us (graceful shutdown), ids do not matter then
+                let _ = ctrl.accept_send.send(AcceptMessage::ReleaseSession(session_id));
+            },
+
+            // sent by the accept task when the operator reloads the config
+            ControlMessage::Reload(stammer_cfg) => ctrl.reload(*stammer_cfg),
+
+            // sent by the admin task on behalf of the operator, who waits for the outcome.
+            // denials are meant for clients, the operator only needs their reason
+            ControlMessage::Admin(request, reply) => {
+                let outcome = ctrl.handle_admin(request).map_err(|err| match err.downcast::<Denied>() {
+                    Ok(denied) => Error::msg(denied.0.get_reason().to_owned()),
+                    Err(err) => err,
+                });
+                // the operator may have hung up already
+                let _ = reply.send(outcome);
+            },
+
+            // sent by the blocking task which checked the password of a pending login
+            ControlMessage::PasswordChecked(session_id, verified) => ctrl.password_checked(session_id, verified),
+
+            // sent by the blocking task which hashed the password of a registering session
+            ControlMessage::PasswordHashed(registration, hash) => {
+                let actor_id = registration.actor_id;
+                if let Err(err) = hash.and_then(|hash| ctrl.complete_registration(registration, hash)) {
+                    match err.downcast::<Denied>() {
+                        Ok(denied) => ctrl.deny(actor_id, denied),
+                        Err(err) => warn!("registration: {}", err),
                     }
                 }
             },
@@ -74,45 +177,995 @@ pub async fn run_control_task(
                 control_recv.close();
             },
         }
+
+        // sessions come and go through the messages above
+        let unauthenticated = ctrl.unauth.len() + ctrl.logins.len();
+        metrics().sessions.with_label_values(&["unauthenticated"]).set(unauthenticated as i64);
+        metrics().sessions.with_label_values(&["authenticated"]).set(ctrl.rtbl.session_count() as i64);
     }
 
     trace!("sending shutdown message to routing task");
-    routing_send.send(RoutingMessage::Shutdown).expect("routing cannot be closed yet");
+    ctrl.routing_send.send(RoutingMessage::Shutdown).expect("routing cannot be closed yet");
 
     trace!("control task stopped")
 }
 
+// whose permissions a change may have changed. a session's own changes (room, voice
+// targets, tokens, registration) only matter to its own permissions, which do not depend
+// on other sessions. acl and room tree changes may matter to everyone's
+#[derive(Clone, Copy, Debug)]
+enum Recompute {
+    Nobody,
+    Session(u32),
+    Everyone,
+}
+
+// the state owned by the control task
+struct Control {
+    stammer_cfg: StammerConfig,
+    // where sessions are stored before they authenticate
+    unauth: HashMap<u32, UnAuthSession>,
+    logins: HashMap<u32, PendingLogin>,
+    // once authenticated, sessions are routable
+    rtbl: RoutingTable,
+    // registered users, and the passwords unregistered sessions would register with
+    registry: Registry,
+    passwords: HashMap<u32, String>,
+    // who may do what, where
+    acl: Acl,
+    // who may not connect at all
+    bans: BanList,
+    // the storage task writes through whenever rooms, users, acls or bans change
+    storage_send: USender<StoredState>,
+    // results of the blocking tasks checking and hashing passwords come back this way
+    control_send: USender<ControlMessage>,
+    // the accept task turns away banned addresses, it needs to know about them
+    accept_send: USender<AcceptMessage>,
+    // the routing task is kept up to date with our routing table
+    routing_send: RoutingSender,
+    // the udp task holds the crypt states of authenticated sessions
+    udp_send: USender<UdpMessage>,
+    // joins, leaves and channel switches are published for outside tooling
+    events: EventBus,
+}
+
 use anyhow::{Error,Result};
-fn handle_packet(
-    session_id: u32,
-    packet: ControlPacket<Serverbound>,
-    unauth: &mut HashMap<u32, UnAuthSession>,
-    routing_send: &mut USender<RoutingMessage>,
-    rtbl: &mut RoutingTable,
-) -> Result<()> {
-    if rtbl.holds_session(session_id) {
+impl Control {
+    fn handle_packet(&mut self, session_id: u32, packet: ControlPacket<Serverbound>) -> Result<()> {
+        if self.rtbl.holds_session(session_id) {
+            match packet {
+                // crypt resyncs are handled by the udp task, which owns the crypt states
+                ControlPacket::CryptSetup(crypt_setup) => {
+                    let msg = UdpMessage::CryptSetup(session_id, crypt_setup);
+                    self.udp_send.send(msg).expect("channel closes only upon later shutdown msg");
+                    Ok(())
+                },
+
+                // whisper/shout targets are resolved by the routing task
+                ControlPacket::VoiceTarget(voice_target) => {
+                    self.rtbl.set_voice_target(session_id, &voice_target)?;
+                    self.update_routing(Recompute::Session(session_id));
+                    Ok(())
+                },
+
+                ControlPacket::ChannelState(channel_state) => self.handle_channel_state(session_id, &channel_state),
+                ControlPacket::ChannelRemove(channel_remove) => self.handle_channel_remove(session_id, &channel_remove),
+                ControlPacket::UserState(user_state) => self.handle_user_state(session_id, &user_state),
+                ControlPacket::ACL(acl) => self.handle_acl(session_id, &acl),
+                ControlPacket::UserRemove(user_remove) => self.handle_user_remove(session_id, &user_remove),
+                ControlPacket::BanList(ban_list) => self.handle_ban_list(session_id, &ban_list),
+
+                // clients send their access tokens again whenever their user edits them
+                ControlPacket::Authenticate(auth) => {
+                    self.rtbl.set_tokens(session_id, auth.get_tokens().to_vec())?;
+                    debug!("session {} updated its access tokens", session_id);
+                    self.update_routing(Recompute::Session(session_id));
+                    self.flush_permissions(session_id);
+                    Ok(())
+                },
+
+                // clients grey out what they cannot do, room by room
+                ControlPacket::PermissionQuery(permission_query) => {
+                    let room_id = permission_query.get_channel_id();
+                    self.rtbl.room_state(room_id).ok_or_else(|| Error::msg(format!("unknown room {}", room_id)))?;
+                    self.send(session_id, self.permission_query(session_id, room_id).into());
+                    Ok(())
+                },
+
+                // clients resolve the user ids of acls into names and back with these
+                ControlPacket::QueryUsers(query_users) => {
+                    let mut answer = msgs::QueryUsers::new();
+                    let by_id = query_users.get_ids().iter().filter_map(|user_id| self.registry.get(*user_id));
+                    let by_name = query_users.get_names().iter().filter_map(|name| self.registry.find(name));
+                    for user in by_id.chain(by_name) {
+                        answer.mut_ids().push(user.id);
+                        answer.mut_names().push(user.name.clone());
+                    }
+                    self.send(session_id, answer.into());
+                    Ok(())
+                },
+
+                _ => Ok(()),
+            }
+        } else if let Some(unauth_session) = self.unauth.remove(&session_id) {
+            if let ControlPacket::Authenticate(auth) = packet {
+                let tokens = auth.get_tokens().to_vec();
+                match self.authenticate(&auth, unauth_session.addr.ip(), unauth_session.cert_hash.as_deref()) {
+                    Ok((name, user_id, None)) => self.log_in(session_id, name, user_id, tokens, unauth_session, auth.get_password()),
+                    Ok((name, user_id, Some(password_hash))) => {
+                        let control_send = self.control_send.clone();
+                        let password = auth.get_password().to_owned();
+                        tokio::task::spawn_blocking(move || {
+                            let verified = password_hash.verify(&password).unwrap_or_else(|err| {
+                                warn!("failed to verify password of session {}: {}", session_id, err);
+                                false
+                            });
+                            // we may be shutting down, the session is gone then anyway
+                            let _ = control_send.send(ControlMessage::PasswordChecked(session_id, verified));
+                        });
+                        self.logins.insert(session_id, PendingLogin{name, user_id, tokens, unauth_session});
+                    },
+                    Err(reject) => self.turn_away(session_id, unauth_session, reject),
+                }
+                Ok(())
+            } else {
+                Err(Error::msg(format!("unauth session {} sent bad packet {:?}", session_id, packet)))
+            }
+        } else {
+            Err(Error::msg(format!("unknown session {} sent packet {:?}", session_id, packet)))
+        }
+    }
+
+    fn password_checked(&mut self, session_id: u32, verified: bool) {
+        let PendingLogin{name, user_id, tokens, unauth_session} = match self.logins.remove(&session_id) {
+            Some(login) => login,
+            None => return debug!("session {} left before its password was checked", session_id),
+        };
+        if !verified {
+            let reject = reject(RejectType::WrongUserPW, format!("wrong password for {}", name));
+            return self.turn_away(session_id, unauth_session, reject)
+        }
+        // others may have connected while the password was checked
+        match self.admit(&name, user_id) {
+            Ok(()) => self.log_in(session_id, name, user_id, tokens, unauth_session, ""),
+            Err(reject) => self.turn_away(session_id, unauth_session, reject),
+        }
+    }
+
+    fn log_in(
+        &mut self,
+        session_id: u32,
+        name: String,
+        user_id: Option<u32>,
+        tokens: Vec<String>,
+        unauth_session: UnAuthSession,
+        password: &str,
+    ) {
+        info!("session {} authenticated itself as {}", session_id, name);
+        self.remember_password(session_id, user_id, password);
+        if let (Some(user_id), Some(cert_hash)) = (user_id, &unauth_session.cert_hash) {
+            if self.registry.adopt_cert(user_id, cert_hash) {
+                info!("user {} will be recognized by its certificate from now on", user_id);
+                self.persist();
+            }
+        }
+        self.enroll_session(session_id, name, user_id, tokens, unauth_session);
+    }
+
+    // the session task hangs up once it forwarded the reject. might fail
+    // if the session is gone already, its RemoveSession is on its way
+    fn turn_away(&self, session_id: u32, unauth_session: UnAuthSession, reject: msgs::Reject) {
+        info!("session {} rejected: {:?}", session_id, reject.get_field_type());
+        metrics().handshake_failed(reject_reason(reject.get_field_type()));
+        let _ = unauth_session.send.send(reject.into());
+    }
+
+    fn set_superuser_password(&mut self) {
+        if let Some(password) = self.stammer_cfg.superuser_password.clone() {
+            match self.registry.set_superuser_password(&password) {
+                Ok(()) => self.persist(),
+                Err(err) => warn!("failed to set the superuser password: {}", err),
+            }
+        }
+    }
+
+    // the new settings apply from now on, connected clients are told about the new limits
+    fn reload(&mut self, stammer_cfg: StammerConfig) {
+        let password_changed = stammer_cfg.superuser_password != self.stammer_cfg.superuser_password;
+        self.stammer_cfg = stammer_cfg;
+        if password_changed {
+            self.set_superuser_password();
+        }
+
+        let msg = UdpMessage::Reload(Box::new(self.stammer_cfg.clone()));
+        self.udp_send.send(msg).expect("channel closes only upon later shutdown msg");
+
+        let mut server_config = self.server_config();
+        server_config.set_max_bandwidth(self.stammer_cfg.max_bandwidth);
+        server_config.set_welcome_text(self.stammer_cfg.welcome_text.clone());
+        self.broadcast(server_config.into(), None);
+    }
+
+    // yields the name the session goes by, which is the registered one if any, along
+    // with its user id and the password hash it has yet to match, or why the session
+    // cannot go by the name it wants. like murmur, registered users are recognized by
+    // their certificate first
+    fn authenticate(
+        &self,
+        auth: &msgs::Authenticate,
+        addr: IpAddr,
+        cert_hash: Option<&str>,
+    ) -> std::result::Result<(String, Option<u32>, Option<PasswordHash>), msgs::Reject> {
+        // the accept task turned away banned addresses, but the ban list may have changed
+        // since, and we only know about the certificate of the client now
+        if let Some(ban) = self.bans.find(addr, cert_hash) {
+            return Err(reject(RejectType::None, format!("you are banned: {}", ban.reason)))
+        }
+        let name = auth.get_username();
+        check_username(&self.stammer_cfg, name)?;
+
+        let user = match self.registry.find(name) {
+            // the superuser name is reserved, even before a password is configured for it
+            None if name.eq_ignore_ascii_case(SUPERUSER_NAME) => {
+                return Err(reject(RejectType::WrongUserPW, "the superuser has no password configured"))
+            },
+            // a known certificate under another name, that is still the registered user
+            None => cert_hash.and_then(|cert_hash| self.registry.find_by_cert(cert_hash)),
+            // a user with a certificate is that certificate, the password is no way around it
+            Some(user) if user.cert_hash.is_some() => {
+                if user.cert_hash.as_deref() != cert_hash {
+                    return Err(reject(RejectType::WrongUserPW, format!("wrong certificate for {}", user.name)))
+                }
+                Some(user)
+            },
+            Some(user) if user.password.is_none() => {
+                return Err(reject(RejectType::WrongUserPW, format!("wrong password for {}", user.name)))
+            },
+            Some(user) => Some(user),
+        };
+
+        // users without a certificate have to match their password
+        let (name, user_id, password_hash) = match user {
+            Some(user) if user.cert_hash.is_none() => (user.name.clone(), Some(user.id), user.password.clone()),
+            Some(user) => (user.name.clone(), Some(user.id), None),
+            None => (name.to_owned(), None, None),
+        };
+        // no need to check a password for a session which would be turned away anyway
+        self.admit(&name, user_id)?;
+        Ok((name, user_id, password_hash))
+    }
+
+    fn admit(&self, name: &str, user_id: Option<u32>) -> std::result::Result<(), msgs::Reject> {
+        if self.rtbl.holds_name(name) {
+            return Err(reject(RejectType::UsernameInUse, format!("{} is already connected", name)))
+        }
+        // like murmur, the superuser gets in even when the server is full
+        let max_users = self.stammer_cfg.max_users;
+        if user_id != Some(SUPERUSER_ID) && self.rtbl.session_count() >= max_users as usize {
+            return Err(reject(RejectType::ServerFull, format!("the server is full ({} users)", max_users)))
+        }
+        Ok(())
+    }
+
+    // unregistered sessions may register later on, with the password they connected with.
+    // it is only hashed then: most sessions never register, and hashing is costly
+    fn remember_password(&mut self, session_id: u32, user_id: Option<u32>, password: &str) {
+        if user_id.is_none() && !password.is_empty() {
+            self.passwords.insert(session_id, password.to_owned());
+        }
+    }
+
+    // channel creation (no id yet) or edition
+    fn handle_channel_state(&mut self, session_id: u32, channel_state: &msgs::ChannelState) -> Result<()> {
+        let room_id = if channel_state.has_channel_id() {
+            let room_id = channel_state.get_channel_id();
+            let edits_room = channel_state.has_name() || channel_state.has_description()
+                || channel_state.has_position() || channel_state.has_max_users();
+            let moves_room = channel_state.has_parent() && self.rtbl.room_parent(room_id) != Some(channel_state.get_parent());
+            if edits_room || moves_room {
+                self.require(session_id, room_id, acl::WRITE)?;
+            }
+            if moves_room {
+                self.require(session_id, channel_state.get_parent(), acl::MAKE_CHANNEL)?;
+            }
+            // linking needs permission on both ends
+            let links = channel_state.get_links_add().iter().chain(channel_state.get_links_remove());
+            for link_id in links {
+                self.require(session_id, room_id, acl::LINK_CHANNEL)?;
+                self.require(session_id, *link_id, acl::LINK_CHANNEL)?;
+            }
+            self.rtbl.update_room(room_id, channel_state)?;
+            room_id
+        } else {
+            if channel_state.get_temporary() {
+                return Err(Denied::new(DenyType::TemporaryChannel, "temporary channels are not supported").into())
+            }
+            self.require(session_id, channel_state.get_parent(), acl::MAKE_CHANNEL)?;
+            let room_id = self.rtbl.create_room(channel_state)?;
+
+            // like murmur, registered users keep control of the rooms they make
+            if let Some(user_id) = self.rtbl.user_id(session_id) {
+                if !self.acl.has_permission(&self.rtbl, session_id, room_id, acl::WRITE) {
+                    self.acl.grant_write(room_id, user_id);
+                }
+            }
+            room_id
+        };
+        self.room_changed(room_id);
+        Ok(())
+    }
+
+    // let everyone know about the room as we now hold it
+    fn room_changed(&mut self, room_id: u32) {
+        self.update_routing(Recompute::Everyone);
+        self.persist();
+        if let Some(room_state) = self.rtbl.room_state(room_id) {
+            self.broadcast(room_state.into(), None);
+        }
+    }
+
+    fn handle_channel_remove(&mut self, session_id: u32, channel_remove: &msgs::ChannelRemove) -> Result<()> {
+        let room_id = channel_remove.get_channel_id();
+        self.require(session_id, room_id, acl::WRITE)?;
+        self.remove_room(room_id, Some(session_id))
+    }
+
+    // the members of removed rooms fall back to the parent room
+    fn remove_room(&mut self, room_id: u32, actor_id: Option<u32>) -> Result<()> {
+        let orig_room_ids: HashMap<u32, u32> = self.rtbl.tree_session_ids(room_id).into_iter().filter_map(|session_id| {
+            self.rtbl.room_id(session_id).ok().map(|orig_room_id| (session_id, orig_room_id))
+        }).collect();
+        let (removed, moved) = self.rtbl.remove_room(room_id)?;
+        self.acl.remove_rooms(&removed);
+        self.update_routing(Recompute::Everyone);
+        self.persist();
+
+        for moved_id in moved {
+            if let Some(mut user_state) = self.rtbl.user_state(moved_id) {
+                if let (Some(from), Ok(name)) = (orig_room_ids.get(&moved_id), self.rtbl.name(moved_id)) {
+                    let (name, from, to) = (name.to_owned(), *from, user_state.get_channel_id());
+                    self.events.publish(Event::ChannelSwitch{session: moved_id, name, from, to});
+                }
+                if let Some(actor_id) = actor_id {
+                    user_state.set_actor(actor_id);
+                }
+                self.broadcast(user_state.into(), None);
+            }
+        }
+        for removed_id in removed {
+            let mut channel_remove = msgs::ChannelRemove::new();
+            channel_remove.set_channel_id(removed_id);
+            self.broadcast(channel_remove.into(), None);
+        }
         Ok(())
-    } else if let Some(unauth_session) = unauth.remove(&session_id) {
-        if let ControlPacket::Authenticate(_auth) = packet {
-            // FIXME we ultimately need a registry of saved
-            // users/rooms to auth this session against
-            info!("session {} authenticated itself", session_id);
-
-            // modify control task routing table
-            rtbl.enroll_session(session_id, unauth_session.version, unauth_session.send);
-            debug!("control task updated its routing table");
-
-            // propagate routing table change to routing task
-            let msg = RoutingMessage::Update(rtbl.clone());
-            routing_send.send(msg).expect("channel closes only upon later shutdown msg");
-
-            // TODO send all the cryptsetup/channel states/user states/server sync to complete
-            // https://mumble-protocol.readthedocs.io/en/latest/establishing_connection.html#
+    }
+
+    // sessions registering, muting, changing rooms, their own or somebody else's
+    fn handle_user_state(&mut self, session_id: u32, user_state: &msgs::UserState) -> Result<()> {
+        let target_id = if user_state.has_session() { user_state.get_session() } else { session_id };
+        // clients send whatever user_id, we pick the actual one
+        if user_state.has_user_id() {
+            self.register_session(target_id, session_id)?;
+        }
+        self.set_flags(target_id, session_id, user_state)?;
+        if user_state.has_channel_id() {
+            let room_id = user_state.get_channel_id();
+            let orig_room_id = self.rtbl.room_id(target_id)?;
+            if orig_room_id != room_id {
+                // like murmur: entering a room, or moving somebody out of a room into another
+                // which they could enter, or into which we could move them anyway
+                if target_id == session_id {
+                    if !self.acl.has_permission(&self.rtbl, session_id, room_id, acl::MOVE) {
+                        self.require(session_id, room_id, acl::ENTER)?;
+                    }
+                } else {
+                    self.require(session_id, orig_room_id, acl::MOVE)?;
+                    if !self.acl.has_permission(&self.rtbl, target_id, room_id, acl::ENTER) {
+                        self.require(session_id, room_id, acl::MOVE)?;
+                    }
+                }
+                self.move_user(target_id, room_id, Some(session_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn move_user(&mut self, session_id: u32, room_id: u32, actor_id: Option<u32>) -> Result<()> {
+        let orig_room_id = self.rtbl.room_id(session_id)?;
+        self.rtbl.move_session(session_id, room_id)?;
+        self.update_routing(Recompute::Session(session_id));
+        let name = self.rtbl.name(session_id)?.to_owned();
+        self.events.publish(Event::ChannelSwitch{session: session_id, name, from: orig_room_id, to: room_id});
+
+        let mut user_state = msgs::UserState::new();
+        user_state.set_session(session_id);
+        if let Some(actor_id) = actor_id {
+            user_state.set_actor(actor_id);
+        }
+        user_state.set_channel_id(room_id);
+        self.broadcast(user_state.into(), None);
+        Ok(())
+    }
+
+    // clients mute/deafen themselves, admins (anybody with MuteDeafen in the room of the
+    // target) mute, deafen, suppress or make priority speakers of others. nothing is
+    // changed unless all of it is allowed, and the changes are broadcast to everyone
+    fn set_flags(&mut self, session_id: u32, actor_id: u32, user_state: &msgs::UserState) -> Result<()> {
+        let orig_flags = self.rtbl.flags(session_id)?;
+        let mut flags = orig_flags.clone();
+
+        if user_state.has_self_mute() || user_state.has_self_deaf() {
+            if session_id != actor_id {
+                return Err(Error::msg(format!("session {} set self flags of {}", actor_id, session_id)))
+            }
+            // like murmur, deafening implies muting, and unmuting implies undeafening
+            if user_state.has_self_mute() {
+                flags.self_mute = user_state.get_self_mute();
+                flags.self_deaf &= flags.self_mute;
+            }
+            if user_state.has_self_deaf() {
+                flags.self_deaf = user_state.get_self_deaf();
+                flags.self_mute |= flags.self_deaf;
+            }
+        }
+
+        let admin_flags = user_state.has_mute() || user_state.has_deaf()
+            || user_state.has_suppress() || user_state.has_priority_speaker();
+        if admin_flags {
+            self.require(actor_id, self.rtbl.room_id(session_id)?, acl::MUTE_DEAFEN)?;
+            if user_state.has_mute() {
+                flags.mute = user_state.get_mute();
+                flags.deaf &= flags.mute;
+            }
+            if user_state.has_deaf() {
+                flags.deaf = user_state.get_deaf();
+                flags.mute |= flags.deaf;
+            }
+            if user_state.has_suppress() {
+                flags.suppress = user_state.get_suppress();
+            }
+            if user_state.has_priority_speaker() {
+                flags.priority_speaker = user_state.get_priority_speaker();
+            }
+        }
+
+        if flags == *orig_flags {
+            return Ok(())
+        }
+        let mut user_state = msgs::UserState::new();
+        user_state.set_session(session_id);
+        user_state.set_actor(actor_id);
+        if flags.self_mute != orig_flags.self_mute { user_state.set_self_mute(flags.self_mute) }
+        if flags.self_deaf != orig_flags.self_deaf { user_state.set_self_deaf(flags.self_deaf) }
+        if flags.mute != orig_flags.mute { user_state.set_mute(flags.mute) }
+        if flags.deaf != orig_flags.deaf { user_state.set_deaf(flags.deaf) }
+        if flags.suppress != orig_flags.suppress { user_state.set_suppress(flags.suppress) }
+        if flags.priority_speaker != orig_flags.priority_speaker {
+            user_state.set_priority_speaker(flags.priority_speaker)
+        }
+        debug!("session {} set the flags of {} to {:?}", actor_id, session_id, flags);
+
+        self.rtbl.set_flags(session_id, flags)?;
+        self.update_routing(Recompute::Nobody);
+        self.broadcast(user_state.into(), None);
+        Ok(())
+    }
+
+    // privileged sessions kick others out, banning them on the way if they want to. the
+    // kicked session learns why from its UserRemove, after which its session task hangs up
+    fn handle_user_remove(&mut self, session_id: u32, user_remove: &msgs::UserRemove) -> Result<()> {
+        let target_id = user_remove.get_session();
+        let perm = if user_remove.get_ban() { acl::BAN } else { acl::KICK };
+        self.require(session_id, ROOT_ROOM_ID, perm)?;
+        if self.rtbl.user_id(target_id) == Some(SUPERUSER_ID) {
+            return Err(Denied::new(DenyType::SuperUser, "the superuser cannot be kicked").into())
+        }
+        self.remove_user(target_id, Some(session_id), user_remove.get_reason(), user_remove.get_ban())
+    }
+
+    // kicks are done by sessions, or by the operator through the admin api
+    fn remove_user(&mut self, session_id: u32, actor_id: Option<u32>, reason: &str, ban: bool) -> Result<()> {
+        let name = self.rtbl.name(session_id)?.to_owned();
+        let actor = actor_id.map_or("the admin api".to_owned(), |actor_id| format!("session {}", actor_id));
+        if ban {
+            let addr = self.rtbl.addr(session_id)?;
+            let cert_hash = self.rtbl.cert_hash(session_id).map(str::to_owned);
+            self.bans.add(Ban::new(addr, name.clone(), cert_hash, reason.to_owned()));
+            info!("{} banned {} ({})", actor, name, addr);
+            self.update_bans();
+        } else {
+            info!("{} kicked {}", actor, name);
+        }
+
+        let mut user_remove = msgs::UserRemove::new();
+        user_remove.set_session(session_id);
+        if let Some(actor_id) = actor_id {
+            user_remove.set_actor(actor_id);
+        }
+        user_remove.set_reason(reason.to_owned());
+        user_remove.set_ban(ban);
+        self.send(session_id, user_remove.clone().into());
+        self.expel_session(session_id, user_remove);
+        Ok(())
+    }
+
+    // what the operator asks through the admin api, with every permission. changes are
+    // broadcast to clients as if a session had made them, only without an actor
+    fn handle_admin(&mut self, request: AdminRequest) -> Result<serde_json::Value> {
+        use serde_json::json;
+        match request {
+            AdminRequest::ListSessions => {
+                let mut session_ids: Vec<u32> = self.rtbl.session_ids().collect();
+                session_ids.sort_unstable();
+                let sessions = session_ids.into_iter().map(|session_id| {
+                    let room_id = self.rtbl.room_id(session_id)?;
+                    let version = self.rtbl.version(session_id)?;
+                    let v = version.get_version();
+                    Ok(json!({
+                        "session": session_id,
+                        "name": self.rtbl.name(session_id)?,
+                        "user_id": self.rtbl.user_id(session_id),
+                        "channel": room_id,
+                        "channel_name": self.rtbl.room_state(room_id).map(|room_state| room_state.get_name().to_owned()),
+                        "address": self.rtbl.addr(session_id)?.to_string(),
+                        "version": format!("{}.{}.{}", v >> 16, v >> 8 & 0xff, v & 0xff),
+                        "release": version.get_release(),
+                        "os": version.get_os(),
+                        "os_version": version.get_os_version(),
+                        // voice packets dropped so far, because the client could not keep up
+                        "dropped_voice_packets": self.rtbl.sender(session_id).map(|sender| sender.dropped()),
+                    }))
+                }).collect::<Result<Vec<_>>>()?;
+                Ok(json!({"sessions": sessions}))
+            },
+
+            AdminRequest::Kick{session, reason, ban} => {
+                self.remove_user(session, None, &reason, ban)?;
+                Ok(json!({}))
+            },
+
+            AdminRequest::Move{session, channel} => {
+                if self.rtbl.room_id(session)? != channel {
+                    info!("the admin api moved session {} to room {}", session, channel);
+                    self.move_user(session, channel, None)?;
+                }
+                Ok(json!({}))
+            },
+
+            AdminRequest::CreateChannel{parent, name} => {
+                let mut channel_state = msgs::ChannelState::new();
+                channel_state.set_parent(parent);
+                channel_state.set_name(name);
+                let room_id = self.rtbl.create_room(&channel_state)?;
+                info!("the admin api created room {} under {}", room_id, parent);
+                self.room_changed(room_id);
+                Ok(json!({"channel": room_id}))
+            },
+
+            AdminRequest::RemoveChannel{channel} => {
+                self.remove_room(channel, None)?;
+                info!("the admin api removed room {}", channel);
+                Ok(json!({}))
+            },
+
+            // unlike those of clients, these are not held to the text policy
+            AdminRequest::Message{text, sessions, channels, trees} => {
+                let mut text_message = msgs::TextMessage::new();
+                text_message.set_message(text);
+                if sessions.is_empty() && channels.is_empty() && trees.is_empty() {
+                    text_message.set_tree_id(vec![ROOT_ROOM_ID]);
+                } else {
+                    text_message.set_session(sessions);
+                    text_message.set_channel_id(channels);
+                    text_message.set_tree_id(trees);
+                }
+
+                let recipient_ids = self.rtbl.text_recipients(&text_message);
+                for recipient_id in &recipient_ids {
+                    self.send(*recipient_id, text_message.clone().into());
+                }
+                self.events.publish(Event::TextMessage{
+                    session: None,
+                    name: None,
+                    message: text_message.get_message().to_owned(),
+                    sessions: text_message.get_session().to_vec(),
+                    channels: text_message.get_channel_id().to_vec(),
+                    trees: text_message.get_tree_id().to_vec(),
+                });
+                Ok(json!({"recipients": recipient_ids.len()}))
+            },
+
+            AdminRequest::DumpRouting => Ok(self.rtbl.dump()),
+        }
+    }
+
+    // clients query the ban list (to edit it) and send it back edited
+    fn handle_ban_list(&mut self, session_id: u32, ban_list: &msgs::BanList) -> Result<()> {
+        self.require(session_id, ROOT_ROOM_ID, acl::BAN)?;
+        if ban_list.get_query() {
+            self.send(session_id, self.bans.ban_list().into());
+            return Ok(())
+        }
+        self.bans.set_ban_list(ban_list)?;
+        info!("session {} edited the ban list", session_id);
+        self.update_bans();
+        Ok(())
+    }
+
+    // hand the edited ban list over to the accept task, and store it
+    fn update_bans(&mut self) {
+        // the accept task may stop before us (graceful shutdown), bans do not matter then
+        let _ = self.accept_send.send(AcceptMessage::UpdateBans(self.bans.clone()));
+        self.persist();
+    }
+
+    // clients query the acl of a room (to edit it) and send it back edited
+    fn handle_acl(&mut self, session_id: u32, acl: &msgs::ACL) -> Result<()> {
+        let room_id = acl.get_channel_id();
+        self.rtbl.room_state(room_id).ok_or_else(|| Error::msg(format!("unknown room {}", room_id)))?;
+        self.require(session_id, room_id, acl::WRITE)?;
+        if acl.get_query() {
+            self.send(session_id, self.acl.acl_state(&self.rtbl, room_id).into());
+            return Ok(())
+        }
+
+        self.acl.set_acl_state(room_id, acl)?;
+        if let Some(user_id) = self.rtbl.user_id(session_id) {
+            if !self.acl.has_permission(&self.rtbl, session_id, room_id, acl::WRITE) {
+                self.acl.grant_write(room_id, user_id);
+            }
+        }
+        info!("session {} edited the acl of room {}", session_id, room_id);
+        self.update_routing(Recompute::Everyone);
+        self.persist();
+
+        // permissions may have changed anywhere below, clients need to query them again
+        for peer_id in self.rtbl.session_ids() {
+            self.flush_permissions(peer_id);
+        }
+        Ok(())
+    }
+
+    // have a client drop the permissions it cached, starting over with its current room
+    fn flush_permissions(&self, session_id: u32) {
+        let mut permission_query = msgs::PermissionQuery::new();
+        permission_query.set_flush(true);
+        self.send(session_id, permission_query.into());
+        if let Ok(room_id) = self.rtbl.room_id(session_id) {
+            self.send(session_id, self.permission_query(session_id, room_id).into());
+        }
+    }
+
+    fn permission_query(&self, session_id: u32, room_id: u32) -> msgs::PermissionQuery {
+        let mut permission_query = msgs::PermissionQuery::new();
+        permission_query.set_channel_id(room_id);
+        permission_query.set_permissions(self.acl.permissions(&self.rtbl, session_id, room_id));
+        permission_query
+    }
+
+    fn require(&self, session_id: u32, room_id: u32, perm: Permissions) -> Result<()> {
+        if self.acl.has_permission(&self.rtbl, session_id, room_id, perm) {
             Ok(())
         } else {
-            Err(Error::msg(format!("unauth session {} sent bad packet {:?}", session_id, packet)))
+            Err(Denied::permission(session_id, room_id, perm).into())
+        }
+    }
+
+    // sessions with the right permission register themselves or somebody else
+    fn register_session(&mut self, session_id: u32, actor_id: u32) -> Result<()> {
+        let perm = if session_id == actor_id { acl::SELF_REGISTER } else { acl::REGISTER };
+        self.require(actor_id, ROOT_ROOM_ID, perm)?;
+        if self.rtbl.user_id(session_id).is_some() {
+            return Err(Denied::text("already registered").into())
+        }
+
+        // murmur only registers certificates, we also take passwords
+        let name = self.rtbl.name(session_id)?.to_owned();
+        match self.passwords.get(&session_id).cloned() {
+            // registration completes once the password is hashed
+            Some(password) => {
+                let control_send = self.control_send.clone();
+                let registration = Registration{session_id, actor_id, name, password};
+                tokio::task::spawn_blocking(move || {
+                    let hash = PasswordHash::new(&registration.password);
+                    // we may be shutting down, nobody is left to register then
+                    let _ = control_send.send(ControlMessage::PasswordHashed(registration, hash));
+                });
+                Ok(())
+            },
+            None if self.rtbl.cert_hash(session_id).is_some() => self.register_user(session_id, actor_id, &name, None),
+            None => {
+                let reason = format!("{} needs a certificate or a password to register", name);
+                let mut denied = Denied::new(DenyType::MissingCertificate, reason);
+                denied.0.set_session(session_id);
+                Err(denied.into())
+            },
         }
+    }
+
+    // the session must still be the one which asked to register, with the same password
+    fn complete_registration(&mut self, registration: Registration, hash: PasswordHash) -> Result<()> {
+        let Registration{session_id, actor_id, name, password} = registration;
+        let same_session = self.rtbl.name(session_id).is_ok_and(|current| *current == name)
+            && self.passwords.get(&session_id) == Some(&password);
+        if !same_session {
+            debug!("session {} left before its password was hashed", session_id);
+            return Ok(())
+        }
+        if self.rtbl.user_id(session_id).is_some() {
+            return Err(Denied::text("already registered").into())
+        }
+        self.register_user(session_id, actor_id, &name, Some(hash))
+    }
+
+    fn register_user(&mut self, session_id: u32, actor_id: u32, name: &str, password: Option<PasswordHash>) -> Result<()> {
+        let cert_hash = self.rtbl.cert_hash(session_id).map(str::to_owned);
+        let user_id = self.registry.register(name, password, cert_hash)?;
+        self.passwords.remove(&session_id);
+        self.rtbl.set_user_id(session_id, user_id)?;
+        self.update_routing(Recompute::Session(session_id));
+        self.persist();
+        info!("session {} registered as user {} ({})", session_id, user_id, name);
+
+        let mut user_state = msgs::UserState::new();
+        user_state.set_session(session_id);
+        user_state.set_actor(actor_id);
+        user_state.set_user_id(user_id);
+        self.broadcast(user_state.into(), None);
+        Ok(())
+    }
+
+    fn enroll_session(
+        &mut self,
+        session_id: u32,
+        name: String,
+        user_id: Option<u32>,
+        tokens: Vec<String>,
+        unauth_session: UnAuthSession,
+    ) {
+        // modify control task routing table
+        let UnAuthSession{addr, version, cert_hash, send} = unauth_session;
+        logging::name_session(session_id, &name);
+        self.events.publish(Event::Join{session: session_id, name: name.clone(), user_id, channel: ROOT_ROOM_ID});
+        self.rtbl.enroll_session(session_id, name, user_id, cert_hash, addr.ip(), version, send.clone());
+        self.rtbl.set_tokens(session_id, tokens).expect("session just enrolled");
+        debug!("control task updated its routing table");
+        self.update_routing(Recompute::Session(session_id));
+
+        // the udp task will decrypt/encrypt this session's voice datagrams
+        // with this crypt state, which we share with the client in CryptSetup
+        use mumble_protocol::crypt::ServerCryptState;
+        let crypt_state = ServerCryptState::generate_new();
+        let crypt_setup = crypt_setup(&crypt_state);
+        let udp_session = UdpSession::new(addr.ip(), crypt_state, send.clone());
+        let msg = UdpMessage::AddSession(session_id, Box::new(udp_session));
+        self.udp_send.send(msg).expect("channel closes only upon later shutdown msg");
+        self.update_user_count();
+
+        // complete the connection establishment sequence with the client, see:
+        // https://mumble-protocol.readthedocs.io/en/latest/establishing_connection.html
+        //
+        // the session task might be gone already, in which case its
+        // RemoveSession message is on its way and we can ignore errors
+        let _ = send.send(crypt_setup.into());
+        for packet in self.server_state(session_id) {
+            let _ = send.send(packet);
+        }
+
+        // let everyone else know about the newcomer
+        for recipient_id in self.rtbl.session_ids().filter(|recipient_id| *recipient_id != session_id) {
+            if let (Some(user_state), Some(sender)) = (self.user_state(session_id, recipient_id), self.rtbl.sender(recipient_id)) {
+                // the recipient might be leaving, its RemoveSession is on its way then
+                let _ = sender.send(user_state.into());
+            }
+        }
+    }
+
+    fn remove_session(&mut self, session_id: u32) {
+        if self.unauth.remove(&session_id).is_some() || self.logins.remove(&session_id).is_some() {
+            return
+        } else if !self.rtbl.holds_session(session_id) {
+            debug!("session {} left without being enrolled (rejected or kicked)", session_id);
+            return
+        }
+        let mut user_remove = msgs::UserRemove::new();
+        user_remove.set_session(session_id);
+        self.expel_session(session_id, user_remove);
+    }
+
+    // forget about an enrolled session, and let everyone else know it is gone
+    fn expel_session(&mut self, session_id: u32, user_remove: msgs::UserRemove) {
+        self.passwords.remove(&session_id);
+        let name = self.rtbl.name(session_id).map(str::to_owned);
+
+        if let Err(err) = self.rtbl.expel_session(session_id) {
+            warn!("failed to expel session {}: {}", session_id, err);
+        } else {
+            info!("expelled session {} from routing table", session_id);
+            if let Ok(name) = name {
+                self.events.publish(Event::Leave{session: session_id, name});
+            }
+            self.update_routing(Recompute::Nobody);
+            let msg = UdpMessage::RemoveSession(session_id);
+            self.udp_send.send(msg).expect("channel closes only upon later shutdown msg");
+            self.update_user_count();
+
+            self.broadcast(user_remove.into(), None);
+        }
+    }
+
+    // everything a freshly authenticated client needs to know after CryptSetup, in protocol order
+    fn server_state(&self, session_id: u32) -> Vec<ControlPacket<Clientbound>> {
+        let mut packets = vec![];
+
+        // we only ever route opus, the celt versions are the ones murmur advertises
+        let mut codec_version = msgs::CodecVersion::new();
+        codec_version.set_alpha(0x8000_000bu32 as i32);
+        codec_version.set_beta(0);
+        codec_version.set_prefer_alpha(true);
+        codec_version.set_opus(true);
+        packets.push(codec_version.into());
+
+        // the whole room tree, parents first so that clients can attach children
+        packets.extend(self.rtbl.room_states().into_iter().map(ControlPacket::from));
+
+        // all connected users, including the newcomer
+        let user_states = self.rtbl.session_ids().filter_map(|peer_id| self.user_state(peer_id, session_id));
+        packets.extend(user_states.map(ControlPacket::from));
+
+        // this is the signal for the client that it is fully synced up
+        let mut server_sync = msgs::ServerSync::new();
+        server_sync.set_session(session_id);
+        server_sync.set_max_bandwidth(self.stammer_cfg.max_bandwidth);
+        server_sync.set_permissions(self.acl.permissions(&self.rtbl, session_id, ROOT_ROOM_ID) as u64);
+        server_sync.set_welcome_text(self.stammer_cfg.welcome_text.clone());
+        packets.push(server_sync.into());
+
+        // what text messages may look like, so that clients can tell their users
+        packets.push(self.server_config().into());
+
+        packets
+    }
+
+    fn server_config(&self) -> msgs::ServerConfig {
+        let mut server_config = msgs::ServerConfig::new();
+        server_config.set_allow_html(self.stammer_cfg.allow_html);
+        server_config.set_message_length(self.stammer_cfg.max_text_len as u32);
+        server_config.set_image_message_length(self.stammer_cfg.max_image_len as u32);
+        server_config.set_max_users(self.stammer_cfg.max_users);
+        server_config
+    }
+
+    // certificate hashes are only shown to admins, who manage registrations
+    fn user_state(&self, session_id: u32, recipient_id: u32) -> Option<msgs::UserState> {
+        let mut user_state = self.rtbl.user_state(session_id)?;
+        if let (true, Some(cert_hash)) = (self.is_admin(recipient_id), self.rtbl.cert_hash(session_id)) {
+            user_state.set_hash(cert_hash.to_owned());
+        }
+        Some(user_state)
+    }
+
+    fn is_admin(&self, session_id: u32) -> bool {
+        self.acl.has_permission(&self.rtbl, session_id, ROOT_ROOM_ID, acl::REGISTER)
+    }
+
+    // the session task might be gone already, in which case its
+    // RemoveSession message is on its way and we can ignore errors
+    fn send(&self, session_id: u32, packet: ControlPacket<Clientbound>) {
+        if let Some(sender) = self.rtbl.sender(session_id) {
+            let _ = sender.send(packet);
+        }
+    }
+
+    // the session task might be gone already, in which case its
+    // RemoveSession message is on its way and we can ignore errors
+    fn deny(&self, session_id: u32, denied: Denied) {
+        debug!("session {} {}", session_id, denied);
+        self.send(session_id, denied.0.into());
+    }
+
+    fn broadcast(&self, packet: ControlPacket<Clientbound>, exclude: Option<u32>) {
+        for sender in self.rtbl.all_senders(exclude) {
+            // an error might arise in case the destination session is in the
+            // process of being dropped (for whatever reason). we just skip it then
+            let _ = sender.send(packet.clone());
+        }
+    }
+
+    // propagate routing table change to routing task, along with what the acl
+    // lets sessions do with their voice and text messages as of this change
+    fn update_routing(&mut self, recompute: Recompute) {
+        let session_ids: Vec<u32> = match recompute {
+            Recompute::Nobody => vec![],
+            Recompute::Session(session_id) => vec![session_id],
+            Recompute::Everyone => self.rtbl.session_ids().collect(),
+        };
+        for session_id in session_ids {
+            let own_room_id = self.rtbl.room_id(session_id).ok();
+            // only worth collecting for the few sessions which whisper/shout
+            let whispers = self.rtbl.has_voice_targets(session_id);
+            let mut speak = false;
+            let mut whisper_rooms = HashSet::new();
+            let mut text_rooms = HashSet::new();
+            for room_id in self.rtbl.room_ids() {
+                let perms = self.acl.permissions(&self.rtbl, session_id, room_id);
+                if Some(room_id) == own_room_id {
+                    speak = perms & acl::SPEAK != 0;
+                }
+                if whispers && perms & acl::WHISPER != 0 {
+                    whisper_rooms.insert(room_id);
+                }
+                if perms & acl::TEXT_MESSAGE != 0 {
+                    text_rooms.insert(room_id);
+                }
+            }
+            self.rtbl.set_permissions(session_id, speak, whisper_rooms, text_rooms);
+        }
+
+        let msg = RoutingMessage::Update(self.rtbl.clone());
+        self.routing_send.send(msg).expect("channel closes only upon later shutdown msg");
+        self.update_channel_metrics();
+    }
+
+    // channels get renamed and removed, their occupancy is rebuilt from scratch
+    fn update_channel_metrics(&self) {
+        let mut occupancy: HashMap<u32, i64> = HashMap::new();
+        for session_id in self.rtbl.session_ids() {
+            if let Ok(room_id) = self.rtbl.room_id(session_id) {
+                *occupancy.entry(room_id).or_default() += 1;
+            }
+        }
+        metrics().channel_users.reset();
+        for room_id in self.rtbl.room_ids() {
+            if let Some(room_state) = self.rtbl.room_state(room_id) {
+                let labels = [room_id.to_string(), room_state.get_name().to_owned()];
+                let users = occupancy.get(&room_id).copied().unwrap_or(0);
+                metrics().channel_users.with_label_values(&[&labels[0], &labels[1]]).set(users);
+            }
+        }
+    }
+
+    // the storage task does the writing, off this task. the state is small enough to be
+    // handed over whole upon every change, clients seldom make any
+    fn persist(&self) {
+        let stored_state = StoredState{
+            rooms: self.rtbl.room_records(),
+            users: self.registry.records(),
+            acls: self.acl.records(),
+            bans: self.bans.records(),
+        };
+        self.storage_send.send(stored_state).expect("storage task stops after the control task");
+    }
+
+    // the udp task advertises our user count to server browsers
+    fn update_user_count(&self) {
+        let msg = UdpMessage::UserCount(self.rtbl.session_count() as u32);
+        self.udp_send.send(msg).expect("channel closes only upon later shutdown msg");
+    }
+}
+
+// names are displayed by all clients, they need to be somewhat sane, and
+// to match what the server is configured to accept
+fn check_username(stammer_cfg: &StammerConfig, name: &str) -> std::result::Result<(), msgs::Reject> {
+    if name.trim().is_empty() {
+        Err(reject(RejectType::InvalidUsername, "empty username"))
+    } else if name.trim() != name || name.chars().any(char::is_control) {
+        Err(reject(RejectType::InvalidUsername, format!("invalid username {:?}", name)))
+    } else if name.chars().count() > stammer_cfg.max_username_len {
+        let reason = format!("usernames are at most {} characters long", stammer_cfg.max_username_len);
+        Err(reject(RejectType::InvalidUsername, reason))
+    } else if !stammer_cfg.username_regex.is_match(name) {
+        Err(reject(RejectType::InvalidUsername, format!("{:?} is not an allowed username", name)))
     } else {
-        Err(Error::msg(format!("unknown session {} sent packet {:?}", session_id, packet)))
+        Ok(())
     }
 }
+
+// the client encrypts with what we decrypt with, and vice versa
+fn crypt_setup(crypt_state: &mumble_protocol::crypt::ServerCryptState) -> msgs::CryptSetup {
+    let mut crypt_setup = msgs::CryptSetup::new();
+    crypt_setup.set_key(crypt_state.get_key().to_vec());
+    crypt_setup.set_client_nonce(crypt_state.get_decrypt_nonce().to_vec());
+    crypt_setup.set_server_nonce(crypt_state.get_encrypt_nonce().to_vec());
+    crypt_setup
+}
@@ -1,6 +1,18 @@
-use super::routing_table::RoutingTable;
-use tokio::sync::mpsc::UnboundedReceiver as UReceiver;
-use mumble_protocol::voice::{VoicePacket,Serverbound};
+use super::routing_table::{RoutingTable,TARGET_LOOPBACK};
+use super::events::{Event,EventBus};
+use super::task_udp::UdpMessage;
+use super::denied::Denied;
+use super::acl;
+use super::logging;
+use super::metrics::metrics;
+use std::collections::{HashMap,HashSet};
+use std::time::{Duration,Instant};
+use tokio::sync::mpsc::{
+    UnboundedSender as USender,
+    UnboundedReceiver as UReceiver,
+    error::SendError,
+};
+use mumble_protocol::voice::{VoicePacket,VoicePacketPayload,Serverbound};
 use mumble_protocol::control::{
     ControlPacket,
     msgs::TextMessage,
@@ -9,78 +21,174 @@ use log::{warn,trace,debug};
 
 #[derive(Debug)]
 pub enum RoutingMessage {
-    Voice(u32, Box<VoicePacket<Serverbound>>),
+    Voice(u32, Transport, Box<VoicePacket<Serverbound>>),
+    UdpAlive(u32),
     Text(u32, Box<TextMessage>),
 
     Update(RoutingTable),
     Shutdown,
 }
 
-pub async fn run_routing_task(mut routing_recv: UReceiver<RoutingMessage>) {
+impl RoutingMessage {
+    // the session this message comes from, for logging purposes
+    fn session_id(&self) -> Option<u32> {
+        match self {
+            Self::Voice(session_id, _, _) | Self::UdpAlive(session_id) | Self::Text(session_id, _) => Some(*session_id),
+            Self::Update(_) | Self::Shutdown => None,
+        }
+    }
+}
+
+// the routing task's inbox, which keeps count of the messages it holds. a growing
+// count means the routing task cannot keep up with the voice coming in
+#[derive(Clone, Debug)]
+pub struct RoutingSender(USender<RoutingMessage>);
+
+pub fn routing_channel() -> (RoutingSender, UReceiver<RoutingMessage>) {
+    use tokio::sync::mpsc::unbounded_channel;
+    let (routing_send, routing_recv) = unbounded_channel();
+    (RoutingSender(routing_send), routing_recv)
+}
+
+impl RoutingSender {
+    // only fails if the routing task is gone
+    pub fn send(&self, msg: RoutingMessage) -> Result<(), SendError<RoutingMessage>> {
+        // counted ahead, the routing task may receive it before we get to count
+        metrics().routing_queue.inc();
+        self.0.send(msg).inspect_err(|_| metrics().routing_queue.dec())
+    }
+}
+
+// the transport a voice packet reached us through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+// silence after which a session talking again is said to start talking, for clients
+// whose last packet of a talk spurt (the one with the terminator bit) got lost
+const TALK_SPURT_GAP: Duration = Duration::from_secs(1);
+
+pub async fn run_routing_task(
+    mut routing_recv: UReceiver<RoutingMessage>,
+    udp_send: USender<UdpMessage>,
+    events: EventBus,
+) {
     trace!("routing task started");
     let mut routing_table = RoutingTable::default();
+    // sessions we can reach over udp. like murmur, we consider the udp path of a session
+    // working as long as it talks to us over udp, and broken once it tunnels voice over tcp
+    let mut udp_sessions = HashSet::new();
+    // sessions in the middle of a talk spurt, with the time of their last audio packet
+    let mut talking: HashMap<u32, Instant> = HashMap::new();
 
     use tokio::stream::StreamExt;
     while let Some(msg) = routing_recv.next().await {
+        metrics().routing_queue.dec();
+        logging::set_session(msg.session_id());
         match msg {
-            // voice messages sent by session tasks
-            RoutingMessage::Voice(session_id, voice_packet) => match *voice_packet {
+            // voice messages sent by session tasks (tunneled) and the udp task
+            RoutingMessage::Voice(session_id, transport, voice_packet) => match *voice_packet {
                 // an audio packet from a session we need to route to the right peers
                 VoicePacket::Audio{target, seq_num, payload, position_info, ..} => {
-                    // yield all senders for this 
+                    let transport_label = match transport {
+                        Transport::Udp => { udp_sessions.insert(session_id); "udp" },
+                        Transport::Tcp => { udp_sessions.remove(&session_id); "tcp" },
+                    };
+                    metrics().voice_packets.with_label_values(&[transport_label]).inc();
+
+                    // talk spurts of sessions which are heard make start talking events
+                    if target != TARGET_LOOPBACK && routing_table.is_heard(session_id) {
+                        let now = Instant::now();
+                        let starts = talking.insert(session_id, now).is_none_or(|last| now - last > TALK_SPURT_GAP);
+                        if let (true, Ok(name), Ok(channel)) = (starts, routing_table.name(session_id), routing_table.room_id(session_id)) {
+                            events.publish(Event::StartTalking{session: session_id, name: name.to_owned(), channel});
+                        }
+                        if let VoicePacketPayload::Opus(_, true) = payload {
+                            talking.remove(&session_id);
+                        }
+                    }
+
+                    // yield all senders for this target, along with how they are reached
                     let peer_senders = match routing_table.target_senders(session_id, target) {
-                        Err(err) => { warn!("failed to route voice packet: {}", err); continue },
+                        Err(err) => {
+                            warn!("failed to route voice packet: {}", err);
+                            metrics().packets_dropped("unroutable", 1);
+                            continue
+                        },
                         Ok(peer_senders) => peer_senders,
                     };
+                    metrics().voice_fanout.observe(peer_senders.len() as f64);
+
+                    for (peer_id, peer_target, peer_sender) in peer_senders {
+                        // reconstruct the voice packet, this time clientbound
+                        use mumble_protocol::voice::Clientbound;
+                        let voice_packet = Box::new(VoicePacket::Audio{
+                            _dst: std::marker::PhantomData::<Clientbound>,
+                            target: peer_target,
+                            session_id,
+                            seq_num,
+                            payload: payload.clone(),
+                            position_info: position_info.clone(),
+                        });
 
-                    // reconstruct the voice packet, this time clientbound
-                    use mumble_protocol::voice::Clientbound;
-                    let voice_packet = Box::new(VoicePacket::Audio{
-                        _dst: std::marker::PhantomData::<Clientbound>,
-                        target,
-                        session_id,
-                        seq_num,
-                        payload,
-                        position_info,
-                    });
-
-                    for peer_sender in peer_senders {
                         // an error might arise in case the destination session is in the
                         // process of being dropped (for whatever reason). we just skip it then
-                        let _ = peer_sender.send(ControlPacket::UDPTunnel(voice_packet.clone()));
+                        if udp_sessions.contains(&peer_id) {
+                            let _ = udp_send.send(UdpMessage::Voice(peer_id, voice_packet));
+                        } else {
+                            let _ = peer_sender.send(ControlPacket::UDPTunnel(voice_packet));
+                        }
                     }
                 },
 
-                // a session and its connection are trying to ping us. in response, we crash!
-                VoicePacket::Ping{..} => unimplemented!("audio ping not supported yet"),
+                // clients measure the latency of their tunneled voice with these, so we echo
+                // them back through the tunnel. udp pings never make it here, the udp task
+                // echoes them itself. might fail if the session is being dropped
+                VoicePacket::Ping{timestamp} => if let Some(sender) = routing_table.sender(session_id) {
+                    let _ = sender.send(ControlPacket::UDPTunnel(Box::new(VoicePacket::Ping{timestamp})));
+                },
             },
 
-            // text message sent by session tasks
+            // sent by the udp task whenever a session pings us over udp
+            RoutingMessage::UdpAlive(session_id) => { udp_sessions.insert(session_id); },
+
+            // text message sent by session tasks, which already applied the server's text policy
             RoutingMessage::Text(session_id, mut text_message) => {
                 text_message.set_actor(session_id); // keep client from spoofing
 
-                // senders to all recipients of the message. any references to sessions and
-                // rooms that have since then disappeared are just dropped silently
-                let peer_senders = text_message.get_session().iter().filter_map(|session_id| {
-                    routing_table.sender(*session_id)
-                }).chain(
-                    text_message.get_channel_id().iter().map(|room_id| {
-                        routing_table.room_senders(*room_id, None)
-                    }).flatten()
-                );
-                // TODO here we are ignoring the text_message tree_ids (root rooms) recipients
-                // because our rooms are not organized/stored as a tree, and we happen to be lazy
-
-                for peer_sender in peer_senders {
+                // like speaking, the control task worked out where the session may write
+                if let Some(room_id) = routing_table.text_denied_room(session_id, &text_message) {
+                    debug!("session {} may not send text messages to room {}", session_id, room_id);
+                    if let Some(sender) = routing_table.sender(session_id) {
+                        let denied = Denied::permission(session_id, room_id, acl::TEXT_MESSAGE);
+                        let _ = sender.send(denied.0.into());
+                    }
+                    continue
+                }
+
+                let peer_ids = routing_table.text_recipients(&text_message);
+                for peer_sender in peer_ids.iter().filter_map(|peer_id| routing_table.sender(*peer_id)) {
                     // an error might arise in case the destination session is in the
                     // process of being dropped (for whatever reason). we just skip it then
                     let _ = peer_sender.send(ControlPacket::TextMessage(text_message.clone()));
                 }
+                events.publish(Event::TextMessage{
+                    session: Some(session_id),
+                    name: routing_table.name(session_id).ok().map(str::to_owned),
+                    message: text_message.get_message().to_owned(),
+                    sessions: text_message.get_session().to_vec(),
+                    channels: text_message.get_channel_id().to_vec(),
+                    trees: text_message.get_tree_id().to_vec(),
+                });
             },
 
             // sent by the control task in case of routing table change
             RoutingMessage::Update(rtbl) => {
                 debug!("routing task updated its routing table");
+                udp_sessions.retain(|session_id| rtbl.holds_session(*session_id));
+                talking.retain(|session_id, _| rtbl.holds_session(*session_id));
                 routing_table = rtbl;
             },
 
@@ -92,5 +200,8 @@ pub async fn run_routing_task(mut routing_recv: UReceiver<RoutingMessage>) {
         }
     }
 
+    trace!("sending shutdown message to udp task");
+    udp_send.send(UdpMessage::Shutdown).expect("udp cannot be closed yet");
+
     trace!("routing task stopped")
 }
@@ -2,51 +2,139 @@ use anyhow::{Error,Result};
 use mumble_protocol::control::ControlPacket;
 use mumble_protocol::control::ServerControlCodec;
 use super::task_control::ControlMessage;
-use super::task_routing::RoutingMessage;
+use super::task_routing::{RoutingMessage,RoutingSender,Transport};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead,AsyncWrite};
 use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::Framed;
+use tokio::sync::watch::Receiver as WReceiver;
 use tokio::sync::mpsc::{
     UnboundedSender as USender,
 };
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
-use log::{trace,warn,info};
+use log::{trace,warn,info,debug};
 use super::StammerConfig;
+use super::metrics::metrics;
 
-pub async fn run_session_task(
-    stammer_cfg: StammerConfig, // the global config of the stammer task
+pub async fn run_tls_session_task(
+    config_recv: WReceiver<StammerConfig>, // the global config of the stammer task, as reloaded
     session_id: u32, // the id of the session this task will babysit
-    mut client_stream: Framed<TcpStream, ServerControlCodec>, // the connection to the client
+    addr: SocketAddr, // the address of the client
+    tls_acceptor: TlsAcceptor, // performs the tls handshake with the client
+    tcp_stream: TcpStream, // the raw connection to the client
     control_send: USender<ControlMessage>, // forward control messages there
-    routing_send: USender<RoutingMessage>, // forward voice messages there
+    routing_send: RoutingSender, // forward voice messages there
+) {
+    // a client which never completes the handshake would otherwise hold
+    // on to this task forever, so we bound it by the session timeout
+    let stammer_cfg = config_recv.borrow().clone();
+    use tokio::time::timeout;
+    let tls_stream = match timeout(stammer_cfg.session_timeout, tls_acceptor.accept(tcp_stream)).await {
+        Ok(Ok(tls_stream)) => tls_stream,
+        Ok(Err(err)) => {
+            warn!("tls handshake for {} failed: {}", session_id, err);
+            metrics().handshake_failed("tls_handshake");
+            // might fail if control task is closed (a graceful shutdown
+            // is in progress), in which case nobody cares about our session
+            let _ = control_send.send(ControlMessage::RemoveSession(session_id));
+            return
+        },
+        Err(_) => {
+            warn!("tls handshake for {} timed out", session_id);
+            metrics().handshake_failed("tls_timeout");
+            // might fail if control task is closed (a graceful shutdown
+            // is in progress), in which case nobody cares about our session
+            let _ = control_send.send(ControlMessage::RemoveSession(session_id));
+            return
+        },
+    };
+    trace!("tls handshake for {} successful", session_id);
+
+    // the leaf certificate the client presented, if any, identifies it
+    use tokio_rustls::rustls::Session;
+    let cert_hash = tls_stream.get_ref().1.get_peer_certificates().and_then(|certs| {
+        certs.first().map(super::tls::cert_hash)
+    });
+
+    let codec = ServerControlCodec::with_max_frame_len(stammer_cfg.max_frame_len);
+    let client_stream = Framed::new(tls_stream, codec);
+    run_session_task(config_recv, session_id, addr, cert_hash, client_stream, control_send, routing_send).await
+}
+
+pub async fn run_session_task<S: AsyncRead + AsyncWrite + Unpin>(
+    config_recv: WReceiver<StammerConfig>, // the global config of the stammer task, as reloaded
+    session_id: u32, // the id of the session this task will babysit
+    addr: SocketAddr, // the address of the client
+    cert_hash: Option<String>, // the hash of the client certificate, if any
+    client_stream: Framed<S, ServerControlCodec>, // the connection to the client
+    control_send: USender<ControlMessage>, // forward control messages there
+    routing_send: RoutingSender, // forward voice messages there
 ) {
     trace!("session task started for {}", session_id);
+    serve_session(config_recv, session_id, addr, cert_hash, client_stream, &control_send, routing_send).await;
+
+    // however the session ended, this is the last message the control task receives
+    // about it. once handled, the control task gives our session id back to the accept
+    // task for reuse. might fail if control task is closed (a graceful shutdown is in
+    // progress), in which case nobody cares about our session
+    let _ = control_send.send(ControlMessage::RemoveSession(session_id));
+    trace!("session task {} stopped", session_id);
+}
+
+async fn serve_session<S: AsyncRead + AsyncWrite + Unpin>(
+    mut config_recv: WReceiver<StammerConfig>,
+    session_id: u32,
+    addr: SocketAddr,
+    cert_hash: Option<String>,
+    mut client_stream: Framed<S, ServerControlCodec>,
+    control_send: &USender<ControlMessage>,
+    routing_send: RoutingSender,
+) {
+    let mut stammer_cfg = config_recv.borrow().clone();
 
     // with the client, which is the first step in the session handshaking process, see:
     // https://mumble-protocol.readthedocs.io/en/latest/establishing_connection.html
     // TODO: join client/server implems and reuse version handshake as a lib exchange versions
     let mut server_version = msgs::Version::new();
-    server_version.set_version(1u32 << 16 | 2u32 << 8 | 4u32); // TODO check
+    server_version.set_version(super::SERVER_VERSION);
     let version = match version_exchange(server_version, &mut client_stream).await {
         Ok(version) => version,
         Err(err) => {
             warn!("version exchange for {} failed: {}", session_id, err);
+            metrics().handshake_failed("version_exchange");
             return
         },
     };
 
-    // setup session input/output and session handler
-    use tokio::sync::mpsc::unbounded_channel;
-    let (session_send, mut session_recv) = unbounded_channel();
+    // clients too old for us are told so right away, before we hang up on them
+    if version.get_version() < stammer_cfg.min_client_version {
+        info!("session {} rejected: client version {:#x} is too old", session_id, version.get_version());
+        metrics().handshake_failed("wrong_version");
+        use super::denied::reject;
+        use msgs::Reject_RejectType as RejectType;
+        let min = stammer_cfg.min_client_version;
+        let reason = format!("your client is too old, {}.{}.{} is required", min >> 16, min >> 8 & 0xff, min & 0xff);
+        use tokio::time::timeout;
+        let packet = reject(RejectType::WrongVersion, reason).into();
+        let _ = timeout(stammer_cfg.max_saturation, client_stream.send(packet)).await;
+        return
+    }
+
+    // setup session input/output and session handler. the control, routing and udp
+    // tasks queue up clientbound packets there, see session_queue for the drop policy
+    use super::session_queue::session_queue;
+    let (session_send, mut session_recv) = session_queue(stammer_cfg.session_queue_size);
 
     // register ourselves to the control task. this will not make this
     // session routable, for that we still need the authenticate packet from
     // the client. it will be handled by the control task at a later time.
     use super::task_control::UnAuthSession;
-    let msg = ControlMessage::AddSession(session_id, UnAuthSession{version, send: session_send});
+    let unauth_session = UnAuthSession{addr, version, cert_hash, send: session_send};
+    let msg = ControlMessage::AddSession(session_id, unauth_session);
     if control_send.send(msg).is_err() { // control task is closed, graceful shutdown in progress
         warn!("session task {} denied (graceful shutdown in progress)", session_id);
-        trace!("session task {} stopped", session_id);
         return
     }
     info!("session {} successfully declared itself", session_id);
@@ -57,6 +145,8 @@ pub async fn run_session_task(
     let mut last_ping = Instant::now();
     use tokio::time::interval;
     let mut keepalive_check = interval(stammer_cfg.session_timeout);
+    use super::text_policy::TextPolicy;
+    let mut text_policy = TextPolicy::new(&stammer_cfg);
 
     loop {
         use tokio::select;
@@ -69,20 +159,21 @@ pub async fn run_session_task(
                     // io error, for now we consider them terminal (TODO refine)
                     Some(Err(err)) => {
                         warn!("session {}: {}", session_id, err);
-                        // might fail if control task is closed (a graceful shutdown
-                        // is in progress), in which case nobody cares about our session
-                        let _ = control_send.send(ControlMessage::RemoveSession(session_id));
+                        // clients sending oversized packets are either broken or malicious.
+                        // we refuse to buffer those, but still tell the client why we hang up
+                        use mumble_protocol::control::FrameTooLarge;
+                        if let Some(too_large) = err.get_ref().and_then(|err| err.downcast_ref::<FrameTooLarge>()) {
+                            let mut reject = msgs::Reject::new();
+                            reject.set_field_type(msgs::Reject_RejectType::None);
+                            reject.set_reason(too_large.to_string());
+                            use tokio::time::timeout;
+                            let _ = timeout(stammer_cfg.max_saturation, client_stream.send(reject.into())).await;
+                        }
                         break
                     },
 
                     // the connection with the client got closed
-                    None => {
-                        warn!("session {}: connection closed", session_id);
-                        // might fail if control task is closed (a graceful shutdown
-                        // is in progress), in which case nobody cares about our session
-                        let _ = control_send.send(ControlMessage::RemoveSession(session_id));
-                        break
-                    },
+                    None => { warn!("session {}: connection closed", session_id); break },
                 };
 
                 match packet {
@@ -90,14 +181,29 @@ pub async fn run_session_task(
                     ControlPacket::UDPTunnel(voice_packet) => {
                         // might fail if routing task is closed (a graceful shutdown
                         // is in progress), in which case we just drop any packets
-                        let _ = routing_send.send(RoutingMessage::Voice(session_id, voice_packet));
+                        let msg = RoutingMessage::Voice(session_id, Transport::Tcp, voice_packet);
+                        let _ = routing_send.send(msg);
                     },
 
-                    // text messages are handled by the routing task too
-                    ControlPacket::TextMessage(text_message) => {
-                        // might fail if routing task is closed (a graceful shutdown
-                        // is in progress), in which case we just drop any packets
-                        let _ = routing_send.send(RoutingMessage::Text(session_id, text_message));
+                    // text messages are handled by the routing task too, once they comply
+                    // with the server's text policy. sanitizing html is costly enough that
+                    // it is best kept off the routing task, which all voice goes through
+                    ControlPacket::TextMessage(mut text_message) => match text_policy.apply(text_message.get_message()) {
+                        Ok(message) => {
+                            text_message.set_message(message);
+                            // might fail if routing task is closed (a graceful shutdown
+                            // is in progress), in which case we just drop any packets
+                            let _ = routing_send.send(RoutingMessage::Text(session_id, text_message));
+                        },
+                        // the message is not forwarded, the client is told why instead
+                        Err(denied) => {
+                            debug!("session {} sent a text message: {}", session_id, denied);
+                            if let Err(err) = client_stream.send(denied.0.into()).await {
+                                // io error, for now we consider them terminal (TODO refine)
+                                warn!("session {}: {}", session_id, err);
+                                break
+                            }
+                        },
                     },
 
                     // ping packet, just return it directly
@@ -113,10 +219,6 @@ pub async fn run_session_task(
                         if let Err(err) = client_stream.send(packet).await {
                             // io error, for now we consider them terminal (TODO refine)
                             warn!("session {}: {}", session_id, err);
-
-                            // might fail if control task is closed (a graceful shutdown
-                            // is in progress), in which case nobody cares about our session
-                            let _ = control_send.send(ControlMessage::RemoveSession(session_id));
                             break
                         }
                     },
@@ -131,25 +233,53 @@ pub async fn run_session_task(
             },
 
             // listen to control packets from the control and routing tasks
-            packet = session_recv.next() => {
+            packet = session_recv.recv() => {
                 let packet = match packet { // sanitize packet
                     Some(packet) => packet,
 
                     // this happens when both control/routing tasks stop and
                     // drop their senders, this is a graceful shutdown event
-                    None => { info!("session task {} stops gracefully", session_id); return },
+                    None => { info!("session task {} stops gracefully", session_id); break },
                 };
 
-                // handling of client-bound packet is simple: we just forward it
-                if let Err(err) = client_stream.send(packet).await {
+                // handling of client-bound packet is simple: we just forward it. a client
+                // which does not read from its connection at all blocks us right here, so
+                // we bound this by the time we are ready to let its queue saturate
+                let rejected = match &packet {
+                    ControlPacket::Reject(_) => true,
+                    ControlPacket::UserRemove(user_remove) => user_remove.get_session() == session_id,
+                    _ => false,
+                };
+                use tokio::time::timeout;
+                match timeout(stammer_cfg.max_saturation, client_stream.send(packet)).await {
+                    Ok(Ok(())) => (),
                     // io error, for now we consider them terminal (TODO refine)
-                    warn!("session {} abort: {}", session_id, err);
+                    Ok(Err(err)) => { warn!("session {} abort: {}", session_id, err); break },
+                    Err(_) => { warn!("session {} stalled, disconnecting", session_id); break },
+                }
 
-                    // might fail if control task is closed (a graceful shutdown
-                    // is in progress), in which case nobody cares about our session
-                    let _ = control_send.send(ControlMessage::RemoveSession(session_id));
+                // the control task rejected or kicked the client, which it now knows why
+                if rejected {
+                    info!("session {} got rejected or kicked, disconnecting", session_id);
                     break
                 }
+
+                // a client which reads, but slower than voice comes in, is just as bad
+                if let Some(saturated_for) = session_recv.saturated_for() {
+                    if saturated_for > stammer_cfg.max_saturation {
+                        warn!("session {} saturated for {:?}, disconnecting", session_id, saturated_for);
+                        break
+                    }
+                }
+            },
+
+            // the operator reloaded the config, the new limits apply from now on
+            Some(reloaded_cfg) = config_recv.recv() => {
+                if reloaded_cfg.session_timeout != stammer_cfg.session_timeout {
+                    keepalive_check = interval(reloaded_cfg.session_timeout);
+                }
+                text_policy = TextPolicy::new(&reloaded_cfg);
+                stammer_cfg = reloaded_cfg;
             },
 
             // check that we recently got a ping every 30s, otherwise drop
@@ -158,23 +288,21 @@ pub async fn run_session_task(
                 if since_last > stammer_cfg.session_timeout {
                     // TODO we might want to send an error/whatever packet to the client here
                     warn!("session {} timed out ({:?} since ping)", session_id, since_last);
-
-                    // might fail if control task is closed (a graceful shutdown
-                    // is in progress), in which case nobody cares about our session
-                    let _ = control_send.send(ControlMessage::RemoveSession(session_id));
                     break
                 }
             },
         }
     }
 
-    trace!("session task {} stopped", session_id);
+    if session_recv.dropped() > 0 {
+        warn!("session {} dropped {} voice packets", session_id, session_recv.dropped());
+    }
 }
 
 use mumble_protocol::control::msgs;
-async fn version_exchange(
+async fn version_exchange<S: AsyncRead + AsyncWrite + Unpin>(
     server_version: msgs::Version,
-    client_stream: &mut Framed<TcpStream, ServerControlCodec>,
+    client_stream: &mut Framed<S, ServerControlCodec>,
 ) -> Result<msgs::Version> {
     // send the server version to the client
     client_stream.send(server_version.into()).await?;
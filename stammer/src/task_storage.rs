@@ -0,0 +1,29 @@
+use super::storage::{Storage,StoredState};
+use tokio::sync::mpsc::UnboundedReceiver as UReceiver;
+use log::{warn,trace};
+
+// the storage task writes the states the control task hands it, so that the control task
+// never waits on the disk. writes are blocking (and synced), so they happen on tokio's
+// blocking threads, one at a time and in order. states that came in while a write was
+// underway are superseded by the last one, which is all that needs writing
+pub async fn run_storage_task(
+    mut storage: Box<dyn Storage>,
+    mut storage_recv: UReceiver<StoredState>,
+) {
+    trace!("storage task started");
+    // the control task drops its sender when it stops, the last state is written regardless
+    while let Some(mut stored_state) = storage_recv.recv().await {
+        while let Ok(newer_state) = storage_recv.try_recv() {
+            stored_state = newer_state;
+        }
+        // a failed write does not stop the server, but what changed since the last
+        // successful one will be lost upon restart
+        storage = tokio::task::spawn_blocking(move || {
+            if let Err(err) = storage.save(&stored_state) {
+                warn!("failed to persist server state: {}", err);
+            }
+            storage
+        }).await.expect("storage writes do not panic");
+    }
+    trace!("storage task stopped");
+}
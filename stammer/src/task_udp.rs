@@ -0,0 +1,280 @@
+use bytes::BytesMut;
+use std::io;
+use std::collections::HashMap;
+use std::net::{IpAddr,SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::net::udp::SendHalf;
+use tokio::sync::mpsc::{
+    UnboundedReceiver as UReceiver,
+};
+use mumble_protocol::control::msgs;
+use mumble_protocol::crypt::{ServerCryptState,MAX_PACKET_SIZE};
+use mumble_protocol::voice::{VoicePacket,Clientbound,Serverbound};
+use super::task_routing::{RoutingMessage,RoutingSender,Transport};
+use log::{trace,warn,debug,info};
+use super::StammerConfig;
+use super::logging;
+use super::metrics::metrics;
+use super::session_queue::SessionSender;
+
+#[derive(Debug)]
+pub enum UdpMessage {
+    AddSession(u32, Box<UdpSession>),
+    RemoveSession(u32),
+    CryptSetup(u32, Box<msgs::CryptSetup>),
+    Voice(u32, Box<VoicePacket<Clientbound>>),
+    UserCount(u32),
+    Reload(Box<StammerConfig>),
+
+    Shutdown,
+}
+
+impl UdpMessage {
+    // the session this message is about, for logging purposes
+    fn session_id(&self) -> Option<u32> {
+        match self {
+            Self::AddSession(session_id, _) | Self::RemoveSession(session_id) => Some(*session_id),
+            Self::CryptSetup(session_id, _) | Self::Voice(session_id, _) => Some(*session_id),
+            Self::UserCount(_) | Self::Reload(_) | Self::Shutdown => None,
+        }
+    }
+}
+
+// a session as seen by the udp task. sessions are added by the control task upon
+// authentication, but we only learn their udp address once they send us a datagram
+pub struct UdpSession {
+    ip: IpAddr,
+    addr: Option<SocketAddr>,
+    crypt_state: ServerCryptState,
+    send: SessionSender,
+}
+
+impl UdpSession {
+    pub fn new(
+        ip: IpAddr, // the ip of the tcp connection, udp datagrams must come from there too
+        crypt_state: ServerCryptState, // its key and nonces were sent to the client in CryptSetup
+        send: SessionSender, // for crypt resyncs, which happen over tcp
+    ) -> Self {
+        Self{ip, addr: None, crypt_state, send}
+    }
+}
+
+// crypt states do not implement Debug, and we would not want keys in our logs anyway
+use std::fmt;
+impl fmt::Debug for UdpSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UdpSession").field("ip", &self.ip).field("addr", &self.addr).finish()
+    }
+}
+
+pub async fn run_udp_task(
+    stammer_cfg: StammerConfig,
+    udp_socket: UdpSocket,
+    mut udp_recv: UReceiver<UdpMessage>,
+    routing_send: RoutingSender,
+) {
+    trace!("udp task started");
+    let (mut socket_recv, socket_send) = udp_socket.split();
+    let mut udp = Udp{
+        stammer_cfg,
+        users: 0,
+        sessions: HashMap::new(),
+        addrs: HashMap::new(),
+        socket_send,
+        routing_send,
+    };
+
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    loop {
+        use tokio::select;
+        use tokio::stream::StreamExt;
+        select! {
+            // encrypted voice datagrams from clients
+            datagram = socket_recv.recv_from(&mut buf) => match datagram {
+                // udp errors are per-datagram (icmp unreachable and friends), not terminal
+                Err(err) => debug!("failed to receive datagram: {}", err),
+                Ok((len, addr)) => udp.handle_datagram(BytesMut::from(&buf[..len]), addr).await,
+            },
+
+            // session changes from the control task, voice from the routing task
+            msg = udp_recv.next() => {
+                logging::set_session(msg.as_ref().and_then(UdpMessage::session_id));
+                match msg {
+                    None => break,
+                    Some(UdpMessage::AddSession(session_id, udp_session)) => {
+                        udp.sessions.insert(session_id, *udp_session);
+                    },
+                    Some(UdpMessage::RemoveSession(session_id)) => udp.remove_session(session_id),
+                    Some(UdpMessage::CryptSetup(session_id, crypt_setup)) => {
+                        udp.resync(session_id, *crypt_setup);
+                    },
+                    Some(UdpMessage::Voice(session_id, voice_packet)) => {
+                        udp.send_voice(session_id, *voice_packet).await;
+                    },
+                    Some(UdpMessage::UserCount(users)) => udp.users = users,
+                    // server browsers see the new limits right away
+                    Some(UdpMessage::Reload(stammer_cfg)) => udp.stammer_cfg = *stammer_cfg,
+
+                    // sent by the routing task in case of a graceful shutdown
+                    Some(UdpMessage::Shutdown) => {
+                        trace!("stopping udp task: draining all remaining messages");
+                        udp_recv.close();
+                    },
+                }
+            },
+        }
+    }
+
+    trace!("udp task stopped")
+}
+
+// the state owned by the udp task
+struct Udp {
+    stammer_cfg: StammerConfig,
+    // the number of authenticated sessions, as last reported by the control task
+    users: u32,
+    sessions: HashMap<u32, UdpSession>,
+    // udp addresses we have already matched to a session
+    addrs: HashMap<SocketAddr, u32>,
+    socket_send: SendHalf,
+    routing_send: RoutingSender,
+}
+
+impl Udp {
+    async fn handle_datagram(&mut self, mut buf: BytesMut, addr: SocketAddr) {
+        logging::set_session(None);
+        // server browsers ping us without a session, in cleartext
+        use mumble_protocol::ping::PingPacket;
+        use std::convert::TryFrom;
+        if let Ok(ping) = PingPacket::try_from(&buf[..]) {
+            self.pong(ping, addr).await;
+            return
+        }
+
+        // the source address identifies the session, unless this is first contact
+        let decrypted = match self.addrs.get(&addr) {
+            Some(session_id) => {
+                let session = self.sessions.get_mut(session_id).expect("addrs out of sync");
+                session.crypt_state.decrypt(&mut buf).ok().map(|packet| (*session_id, packet))
+            },
+            None => self.trial_decrypt(&buf, addr),
+        };
+
+        // the crypt state keeps track of good, late and lost packets as it decrypts
+        if let Some((session_id, _)) = &decrypted {
+            logging::set_session(Some(*session_id));
+            let crypt_state = &self.sessions[session_id].crypt_state;
+            metrics().set_crypt_stats(*session_id, crypt_state.get_good(), crypt_state.get_late(), crypt_state.get_lost());
+        }
+
+        let (session_id, voice_packet) = match decrypted {
+            Some((session_id, Ok(voice_packet))) => (session_id, voice_packet),
+            Some((session_id, Err(err))) => {
+                debug!("session {} sent bad voice datagram: {}", session_id, err);
+                return
+            },
+            None => {
+                trace!("dropping undecryptable datagram from {}", addr);
+                metrics().packets_dropped("undecryptable", 1);
+                return
+            },
+        };
+
+        match voice_packet {
+            // pings are how clients find out whether udp works, so echo them right away
+            VoicePacket::Ping{timestamp} => {
+                self.send_voice(session_id, VoicePacket::Ping{timestamp}).await;
+                let _ = self.routing_send.send(RoutingMessage::UdpAlive(session_id));
+            },
+
+            // audio is routed like its tcp-tunneled counterpart. might fail if routing
+            // task is closed (a graceful shutdown is in progress), we drop packets then
+            voice_packet => {
+                let msg = RoutingMessage::Voice(session_id, Transport::Udp, Box::new(voice_packet));
+                let _ = self.routing_send.send(msg);
+            },
+        }
+    }
+
+    // try the crypt state of every session connected from the datagram's ip. the first one
+    // that decrypts it successfully owns the address from then on (nat rebinding included)
+    fn trial_decrypt(
+        &mut self,
+        buf: &BytesMut,
+        addr: SocketAddr,
+    ) -> Option<(u32, Result<VoicePacket<Serverbound>, io::Error>)> {
+        let candidates = self.sessions.iter_mut().filter(|(_, session)| session.ip == addr.ip());
+        for (session_id, session) in candidates {
+            if let Ok(voice_packet) = session.crypt_state.decrypt(&mut buf.clone()) {
+                info!("session {} reached us over udp from {}", session_id, addr);
+                if let Some(prev_addr) = session.addr.replace(addr) {
+                    self.addrs.remove(&prev_addr);
+                }
+                self.addrs.insert(addr, *session_id);
+                return Some((*session_id, voice_packet))
+            }
+        }
+        None
+    }
+
+    async fn pong(&mut self, ping: mumble_protocol::ping::PingPacket, addr: SocketAddr) {
+        use mumble_protocol::ping::PongPacket;
+        let pong: [u8; 24] = PongPacket{
+            id: ping.id,
+            version: super::SERVER_VERSION,
+            users: self.users,
+            max_users: self.stammer_cfg.max_users,
+            bandwidth: self.stammer_cfg.max_bandwidth,
+        }.into();
+        if let Err(err) = self.socket_send.send_to(&pong, &addr).await {
+            debug!("failed to answer ping from {}: {}", addr, err);
+        }
+    }
+
+    async fn send_voice(&mut self, session_id: u32, voice_packet: VoicePacket<Clientbound>) {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return, // session is gone, nobody is listening anymore
+        };
+        let addr = match session.addr {
+            Some(addr) => addr,
+            None => return, // the routing task only sends here once we heard from them
+        };
+
+        let mut buf = BytesMut::new();
+        session.crypt_state.encrypt(voice_packet, &mut buf);
+        if let Err(err) = self.socket_send.send_to(&buf, &addr).await {
+            debug!("failed to send datagram to session {}: {}", session_id, err);
+        }
+    }
+
+    fn remove_session(&mut self, session_id: u32) {
+        metrics().forget_crypt_stats(session_id);
+        if let Some(session) = self.sessions.remove(&session_id) {
+            if let Some(addr) = session.addr {
+                self.addrs.remove(&addr);
+            }
+        }
+    }
+
+    // either side may lose track of the other's nonce, which is re-synced over tcp:
+    //
+    //  - an empty CryptSetup means the client wants our encrypt nonce
+    //  - otherwise the client is sending us the nonce it encrypts with
+    fn resync(&mut self, session_id: u32, crypt_setup: msgs::CryptSetup) {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => { warn!("crypt resync for unknown session {}", session_id); return },
+        };
+
+        use std::convert::TryInto;
+        match crypt_setup.get_client_nonce().try_into() {
+            Ok(client_nonce) => session.crypt_state.set_decrypt_nonce(&client_nonce),
+            Err(_) => {
+                let mut crypt_setup = msgs::CryptSetup::new();
+                crypt_setup.set_server_nonce(session.crypt_state.get_encrypt_nonce().to_vec());
+                let _ = session.send.send(crypt_setup.into());
+            },
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use ammonia::Builder;
+use mumble_protocol::control::msgs;
+use super::StammerConfig;
+use super::denied::Denied;
+use msgs::PermissionDenied_DenyType as DenyType;
+
+// what text messages may contain. html goes through an allow-list, which keeps the
+// formatting clients produce and drops anything else (scripts, styles, event handlers...)
+pub struct TextPolicy {
+    // in characters, 0 means unlimited. images count towards the image length only
+    max_text_len: usize,
+    max_image_len: usize,
+    // html is reduced to its text if not allowed at all
+    html: Option<Builder<'static>>,
+    plain: Builder<'static>,
+    without_images: Builder<'static>,
+}
+
+impl TextPolicy {
+    pub fn new(stammer_cfg: &StammerConfig) -> Self {
+        Self::with_limits(stammer_cfg.max_text_len, stammer_cfg.max_image_len, stammer_cfg.allow_html)
+    }
+
+    fn with_limits(max_text_len: usize, max_image_len: usize, allow_html: bool) -> Self {
+        // clients embed pasted images as data urls
+        let mut html = Builder::default();
+        html.add_url_schemes(&["data"]);
+        let mut plain = Builder::default();
+        plain.tags(Default::default());
+        let mut without_images = Builder::default();
+        without_images.rm_tags(&["img"]);
+
+        Self{
+            max_text_len,
+            max_image_len,
+            html: if allow_html { Some(html) } else { None },
+            plain,
+            without_images,
+        }
+    }
+
+    // the message as it may be forwarded, or why it may not
+    pub fn apply(&self, message: &str) -> Result<String, Denied> {
+        let message = match &self.html {
+            Some(html) => html.clean(message).to_string(),
+            None => self.plain.clean(message).to_string(),
+        };
+
+        let len = message.chars().count();
+        let text_len = match self.html {
+            Some(_) => self.without_images.clean(&message).to_string().chars().count(),
+            None => len,
+        };
+        if self.max_text_len != 0 && text_len > self.max_text_len {
+            return Err(Denied::new(DenyType::TextTooLong, format!("over {} characters", self.max_text_len)))
+        }
+        if self.max_image_len != 0 && len > text_len && len > self.max_image_len {
+            return Err(Denied::new(DenyType::TextTooLong, format!("images over {} characters", self.max_image_len)))
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IMAGE: &str = "<img src=\"data:image/png;base64,iVBORw0KGgo=\">";
+
+    fn deny_type(outcome: Result<String, Denied>) -> DenyType {
+        outcome.expect_err("denied").0.get_field_type()
+    }
+
+    #[test]
+    fn html_keeps_formatting_only() {
+        let policy = TextPolicy::with_limits(0, 0, true);
+        assert_eq!(policy.apply("<b>hi</b> <a href=\"https://example.org\">there</a>").unwrap(),
+            "<b>hi</b> <a href=\"https://example.org\" rel=\"noopener noreferrer\">there</a>");
+        assert_eq!(policy.apply("hi<script>alert(1)</script>").unwrap(), "hi");
+        assert_eq!(policy.apply("<p onclick=\"alert(1)\" style=\"color: red\">hi</p>").unwrap(), "<p>hi</p>");
+        assert_eq!(policy.apply(IMAGE).unwrap(), IMAGE);
+    }
+
+    #[test]
+    fn plain_text_keeps_text_only() {
+        let policy = TextPolicy::with_limits(0, 0, false);
+        assert_eq!(policy.apply("<b>hi</b><script>alert(1)</script>").unwrap(), "hi");
+        assert_eq!(policy.apply(&format!("look: {}", IMAGE)).unwrap(), "look: ");
+    }
+
+    #[test]
+    fn images_count_towards_their_own_length() {
+        let message = format!("{}{}", "a".repeat(10), IMAGE);
+        // the text is short enough, whatever the size of the image
+        assert!(TextPolicy::with_limits(10, 0, true).apply(&message).is_ok());
+        assert_eq!(deny_type(TextPolicy::with_limits(9, 0, true).apply(&message)), DenyType::TextTooLong);
+        // the image length is that of the whole message
+        let len = message.chars().count();
+        assert!(TextPolicy::with_limits(10, len, true).apply(&message).is_ok());
+        assert_eq!(deny_type(TextPolicy::with_limits(10, len - 1, true).apply(&message)), DenyType::TextTooLong);
+        // a message without images is not held to the image length
+        assert!(TextPolicy::with_limits(20, 5, true).apply(&"a".repeat(20)).is_ok());
+        assert!(TextPolicy::with_limits(20, 5, false).apply(&"a".repeat(20)).is_ok());
+        assert_eq!(deny_type(TextPolicy::with_limits(20, 5, false).apply(&"a".repeat(21))), DenyType::TextTooLong);
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::{Error,Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{Certificate,PrivateKey,ServerConfig};
+use super::TlsConfig;
+
+impl TlsConfig {
+    // build the acceptor used by the accept task to terminate tls on incoming connections
+    pub fn load_acceptor(&self) -> Result<TlsAcceptor> {
+        let cert_chain = load_certs(&self.cert_path)?;
+        let key = load_key(&self.key_path)?;
+
+        let mut server_cfg = ServerConfig::new(Arc::new(AnyClientCert));
+        server_cfg.set_single_cert(cert_chain, key)?;
+        Ok(TlsAcceptor::from(Arc::new(server_cfg)))
+    }
+}
+
+// the identity of a client, the hex-encoded sha1 of its certificate like murmur has it
+pub fn cert_hash(cert: &Certificate) -> String {
+    use openssl::sha::sha1;
+    hex::encode(sha1(&cert.0))
+}
+
+// mumble client certificates are self-signed, there is nothing to verify them against.
+// they only serve as stable identities, which the control task matches against the
+// registry. rustls still checks that clients hold the private key of their certificate
+struct AnyClientCert;
+
+use tokio_rustls::rustls::{ClientCertVerified,ClientCertVerifier,DistinguishedNames,TLSError};
+use tokio_rustls::webpki::DNSName;
+impl ClientCertVerifier for AnyClientCert {
+    // clients without certificates are welcome, they just cannot be recognized by it
+    fn client_auth_mandatory(&self, _sni: Option<&DNSName>) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self, _sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _presented_certs: &[Certificate],
+        _sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    use tokio_rustls::rustls::internal::pemfile::certs;
+    let mut reader = BufReader::new(File::open(path)?);
+    let cert_chain = certs(&mut reader).map_err(|_| {
+        Error::msg(format!("failed to parse certificates from {}", path.display()))
+    })?;
+
+    if cert_chain.is_empty() {
+        Err(Error::msg(format!("no certificate found in {}", path.display())))
+    } else {
+        Ok(cert_chain)
+    }
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    // keys may come pkcs8-encoded ("BEGIN PRIVATE KEY") or in the
+    // older rsa format ("BEGIN RSA PRIVATE KEY"), we support both
+    use tokio_rustls::rustls::internal::pemfile::{pkcs8_private_keys,rsa_private_keys};
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+
+    keys.into_iter().next().ok_or_else(|| {
+        Error::msg(format!("no private key found in {}", path.display()))
+    })
+}
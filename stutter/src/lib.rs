@@ -2,6 +2,7 @@ use tokio::net::TcpStream;
 use std::time::Duration;
 use anyhow::Result;
 use log::{error,trace,info,debug};
+use log_setup::{LogLevels,LogFormat,LogOutput};
 use tokio::sync::mpsc::{
     UnboundedReceiver as UReceiver,
     UnboundedSender as USender,
@@ -11,6 +12,10 @@ use tokio::sync::mpsc::{
 pub struct StutterConfig {
     pub addr: String,
     pub session_timeout: Duration,
+    pub log_levels: LogLevels,
+    pub log_format: LogFormat,
+    // the terminal belongs to the ui, logs may go to a file or syslog instead
+    pub log_output: LogOutput,
 }
 
 pub async fn run_stutter_task(
@@ -88,9 +93,15 @@ impl StutterConfig {
     pub fn from_env() -> Result<Self> {
         use std::env::var;
         let session_timeout = var("STUTTER_SESSION_TIMEOUT_SECS").unwrap_or("30".to_owned());
+        let log_levels = var("STUTTER_LOG_LEVEL").unwrap_or("info".to_owned());
+        let log_format = var("STUTTER_LOG_FORMAT").unwrap_or("text".to_owned());
+        let log_output = var("STUTTER_LOG_OUTPUT").unwrap_or("stderr".to_owned());
         Ok(Self {
             addr: var("STUTTER_ADDR").unwrap_or("localhost:8792".to_owned()),
             session_timeout: Duration::from_secs(session_timeout.parse::<u64>()?),
+            log_levels: log_levels.parse::<LogLevels>()?,
+            log_format: log_format.parse::<LogFormat>()?,
+            log_output: log_output.parse::<LogOutput>()?,
         })
     }
 }
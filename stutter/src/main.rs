@@ -3,17 +3,21 @@ use log::error;
 
 #[tokio::main]
 async fn main() {
-    if let Err(err) = setup_logging().await {
+    // the config comes first, it says how much to log
+    use stutter::StutterConfig;
+    let stutter_cfg = match StutterConfig::from_env() {
+        Ok(stutter_cfg) => stutter_cfg,
+        Err(err) => { eprintln!("failed to load config: {}", err); return },
+    };
+
+    if let Err(err) = setup_logging(&stutter_cfg).await {
         eprintln!("failed to setup logging: {}", err);
-    } else if let Err(err) = run_stutter().await {
+    } else if let Err(err) = run_stutter(stutter_cfg).await {
         error!("error while running stutter: {}", err);
     }
 }
 
-async fn run_stutter() -> Result<()> {
-    use stutter::StutterConfig;
-    let stutter_cfg = StutterConfig::from_env()?;
-
+async fn run_stutter(stutter_cfg: stutter::StutterConfig) -> Result<()> {
     use tokio::net::TcpStream;
     let server_stream = TcpStream::connect(&stutter_cfg.addr).await?;
 
@@ -23,12 +27,11 @@ async fn run_stutter() -> Result<()> {
     Ok(())
 }
 
-async fn setup_logging() -> Result<()> {
-    use std::io::stderr;
-    use fern::Dispatch;
-    use log::LevelFilter;
+async fn setup_logging(stutter_cfg: &stutter::StutterConfig) -> Result<()> {
     use log::info;
-    Dispatch::new().level(LevelFilter::Trace).chain(stderr()).apply()?;
-    info!("logging to stderr setup successfully");
+    // stutter is one session, its lines need no context
+    let (levels, format, output) = (&stutter_cfg.log_levels, stutter_cfg.log_format, &stutter_cfg.log_output);
+    log_setup::setup_logging("stutter", levels, format, output, || None)?;
+    info!("logging setup successfully");
     Ok(())
 }
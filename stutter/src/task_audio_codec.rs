@@ -95,8 +95,8 @@ pub async fn run_audio_codec_task(
                     _ => continue,
                 },
 
-                // this is a pong from the server
-                VoicePacket::Ping{..} => unimplemented!("audio pings not supported yet"),
+                // voice pings are answered by the connection task, they should not get here
+                VoicePacket::Ping{..} => trace!("ignoring voice ping"),
             },
         }
     }
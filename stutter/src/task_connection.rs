@@ -1,13 +1,19 @@
 use anyhow::Result;
+use bytes::BytesMut;
 use mumble_protocol::control::{ClientControlCodec,ControlPacket,msgs};
-use mumble_protocol::voice::{VoicePacket,Serverbound};
+use mumble_protocol::crypt::{ClientCryptState,MAX_PACKET_SIZE};
+use mumble_protocol::voice::{VoicePacket,Clientbound,Serverbound};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration,Instant};
 use tokio::sync::mpsc::{
     UnboundedReceiver as UReceiver,
     UnboundedSender as USender,
 };
 use tokio_util::codec::Framed;
 use tokio::net::TcpStream;
-use log::{error,trace,info,debug};
+use tokio::net::udp::{RecvHalf,SendHalf};
+use log::{error,trace,info,debug,warn};
 
 pub enum ConnectionMessage {
     Voice(VoicePacket<Serverbound>),
@@ -32,6 +38,14 @@ pub async fn run_connection_task(
     let ping_interval = stutter_cfg.session_timeout / 2;
     let mut keepalive = interval(ping_interval);
 
+    // voice is tunneled over tcp until the server sends us the udp crypt keys
+    // and we get our voice pings echoed back over udp, see VoicePath
+    let server_addr = server_stream.get_ref().peer_addr()?;
+    let mut voice_path = VoicePath::new(server_addr);
+    let mut voice_keepalive = interval(VOICE_PING_INTERVAL);
+    let mut udp_recv: Option<RecvHalf> = None;
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+
     loop {
         use tokio::select;
         use futures::sink::SinkExt;
@@ -40,12 +54,38 @@ pub async fn run_connection_task(
             // reminder to send a ping to the server
             _ = keepalive.next() => ping(&mut server_stream).await?,
 
+            // reminder to ping the server over both voice transports
+            _ = voice_keepalive.next() => {
+                let ping = voice_path.ping();
+                voice_path.send_udp(ping.clone()).await;
+                server_stream.send(ControlPacket::UDPTunnel(Box::new(ping))).await?;
+            },
+
+            // encrypted voice datagrams from the server
+            datagram = recv_datagram(&mut udp_recv, &mut buf) => match datagram {
+                // udp errors are per-datagram (icmp unreachable and friends), not terminal
+                Err(err) => debug!("failed to receive datagram: {}", err),
+                Ok(len) => match voice_path.decrypt(BytesMut::from(&buf[..len])) {
+                    None => trace!("dropping undecryptable datagram"),
+                    Some(VoicePacket::Ping{timestamp}) => voice_path.pong(Transport::Udp, timestamp),
+                    Some(voice_packet) => {
+                        // this might happen if the caller has started shutting down when we
+                        // receive this packet. it's not accepting any new packets, so we drop it
+                        let _ = audio_codec_sender.send(AudioCodecMessage::Inbound(voice_packet));
+                    },
+                },
+            },
+
             // messages from audio codec or ui tasks are handled here
             connection_msg = connection_recver.next() => match connection_msg {
                 // audio codec and ui tasks are goners, we are done here
                 None => { trace!("other tasks are shutting down, gracefully stopping"); break },
 
-                // received a voice message from the audio codec task
+                // received a voice message from the audio codec task, which goes
+                // over udp as long as it works, and is tunneled over tcp otherwise
+                Some(ConnectionMessage::Voice(voice_packet)) if voice_path.udp_healthy() => {
+                    voice_path.send_udp(voice_packet).await;
+                },
                 Some(ConnectionMessage::Voice(voice_packet)) => {
                     let msg = ControlPacket::UDPTunnel(Box::new(voice_packet));
                     // we consider io errors terminal at this time (TODO refine)
@@ -69,6 +109,14 @@ pub async fn run_connection_task(
                 // we consider all io errors terminal for now (TODO refine)
                 Some(Err(err)) => { error!("connection error: {}, stopping", err); break },
 
+                // the server is echoing one of our tunneled voice pings
+                Some(Ok(ControlPacket::UDPTunnel(voice_packet)))
+                    if matches!(*voice_packet, VoicePacket::Ping{..}) => {
+                    if let VoicePacket::Ping{timestamp} = *voice_packet {
+                        voice_path.pong(Transport::Tcp, timestamp);
+                    }
+                },
+
                 // the server is sending us voice data, forward to the audio codec task
                 Some(Ok(ControlPacket::UDPTunnel(voice_packet))) => {
                     let msg = AudioCodecMessage::Inbound(*voice_packet);
@@ -82,6 +130,25 @@ pub async fn run_connection_task(
                     debug!("received pong from server: {}", ping.get_timestamp());
                 },
 
+                // an empty crypt setup means the server lost track of our encrypt nonce
+                Some(Ok(ControlPacket::CryptSetup(crypt_setup)))
+                    if !crypt_setup.has_key() && !crypt_setup.has_server_nonce() => {
+                    if let Some(encrypt_nonce) = voice_path.encrypt_nonce() {
+                        let mut crypt_setup = msgs::CryptSetup::new();
+                        crypt_setup.set_client_nonce(encrypt_nonce.to_vec());
+                        server_stream.send(crypt_setup.into()).await?;
+                    }
+                },
+
+                // the server is setting up (or resyncing) our udp voice crypt state
+                Some(Ok(ControlPacket::CryptSetup(crypt_setup))) => {
+                    match voice_path.crypt_setup(*crypt_setup).await {
+                        Ok(Some(recv)) => udp_recv = Some(recv),
+                        Ok(None) => (),
+                        Err(err) => warn!("udp voice unavailable, tunneling over tcp: {}", err),
+                    }
+                },
+
                 // we forward to the ui all other control messages
                 Some(Ok(_packet)) => {
                     // this might happen if the caller has started shutting down when we receive
@@ -96,6 +163,137 @@ pub async fn run_connection_task(
     Ok(())
 }
 
+// receive from the udp socket once we have one, never returns until then
+async fn recv_datagram(udp_recv: &mut Option<RecvHalf>, buf: &mut [u8]) -> io::Result<usize> {
+    match udp_recv {
+        Some(udp_recv) => udp_recv.recv(buf).await,
+        None => futures::future::pending().await,
+    }
+}
+
+// voice pings are sent this often over both transports. udp is considered
+// healthy as long as its pings keep coming back, with a bit of tolerance
+const VOICE_PING_INTERVAL: Duration = Duration::from_secs(5);
+const UDP_PINGS_TOLERATED: u32 = 3;
+
+#[derive(Clone, Copy, Debug)]
+enum Transport {
+    Tcp,
+    Udp,
+}
+
+// the state of our voice connection to the server
+struct VoicePath {
+    server_addr: SocketAddr,
+    // voice ping timestamps are microseconds elapsed since this instant
+    start: Instant,
+    // set up once the server sends us our keys in CryptSetup
+    udp: Option<(SendHalf, ClientCryptState)>,
+    last_udp_pong: Option<Instant>,
+}
+
+impl VoicePath {
+    fn new(server_addr: SocketAddr) -> Self {
+        Self{
+            server_addr,
+            start: Instant::now(),
+            udp: None,
+            last_udp_pong: None,
+        }
+    }
+
+    fn udp_healthy(&self) -> bool {
+        matches!(self.last_udp_pong, Some(last_udp_pong)
+            if last_udp_pong.elapsed() < VOICE_PING_INTERVAL * UDP_PINGS_TOLERATED)
+    }
+
+    fn ping(&mut self) -> VoicePacket<Serverbound> {
+        if self.last_udp_pong.is_some() && !self.udp_healthy() {
+            warn!("udp voice pings went unanswered, tunneling voice over tcp");
+            self.last_udp_pong = None;
+        }
+        VoicePacket::Ping{timestamp: self.start.elapsed().as_micros() as u64}
+    }
+
+    fn pong(&mut self, transport: Transport, timestamp: u64) {
+        let rtt = match self.start.elapsed().checked_sub(Duration::from_micros(timestamp)) {
+            Some(rtt) => rtt,
+            None => { warn!("server echoed a voice ping we never sent"); return },
+        };
+        debug!("voice ping round trip over {:?}: {:?}", transport, rtt);
+
+        if let Transport::Udp = transport {
+            if !self.udp_healthy() {
+                info!("udp voice path is up, sending voice over udp");
+            }
+            self.last_udp_pong = Some(Instant::now());
+        }
+    }
+
+    async fn send_udp(&mut self, voice_packet: VoicePacket<Serverbound>) {
+        if let Some((udp_send, crypt_state)) = &mut self.udp {
+            let mut buf = BytesMut::new();
+            crypt_state.encrypt(voice_packet, &mut buf);
+            // udp errors are per-datagram, the voice pings will tell us if this keeps failing
+            if let Err(err) = udp_send.send(&buf).await {
+                debug!("failed to send datagram: {}", err);
+            }
+        }
+    }
+
+    fn decrypt(&mut self, mut buf: BytesMut) -> Option<VoicePacket<Clientbound>> {
+        let (_, crypt_state) = self.udp.as_mut()?;
+        match crypt_state.decrypt(&mut buf) {
+            Ok(Ok(voice_packet)) => Some(voice_packet),
+            Ok(Err(err)) => { debug!("server sent bad voice datagram: {}", err); None },
+            Err(_) => None,
+        }
+    }
+
+    fn encrypt_nonce(&self) -> Option<[u8; 16]> {
+        self.udp.as_ref().map(|(_, crypt_state)| crypt_state.get_encrypt_nonce())
+    }
+
+    // the first full crypt setup binds our udp socket, whose receiving half is returned.
+    // later ones either replace our keys or resync the server's (our decrypt) nonce
+    async fn crypt_setup(&mut self, crypt_setup: msgs::CryptSetup) -> Result<Option<RecvHalf>> {
+        use std::convert::TryInto;
+        let key = crypt_setup.get_key().try_into();
+        let client_nonce = crypt_setup.get_client_nonce().try_into();
+        let server_nonce = crypt_setup.get_server_nonce().try_into();
+
+        match (key, client_nonce, server_nonce) {
+            (Ok(key), Ok(client_nonce), Ok(server_nonce)) => {
+                let crypt_state = ClientCryptState::new_from(key, client_nonce, server_nonce);
+                if let Some((_, prev_crypt_state)) = &mut self.udp {
+                    *prev_crypt_state = crypt_state;
+                    return Ok(None)
+                }
+
+                // the server listens for udp on the same address as tcp
+                use tokio::net::UdpSocket;
+                let bind_addr = if self.server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let udp_socket = UdpSocket::bind(bind_addr).await?;
+                udp_socket.connect(self.server_addr).await?;
+                info!("udp voice socket bound to {}", udp_socket.local_addr()?);
+
+                let (udp_recv, udp_send) = udp_socket.split();
+                self.udp = Some((udp_send, crypt_state));
+                Ok(Some(udp_recv))
+            },
+
+            (_, _, Ok(server_nonce)) => {
+                if let Some((_, crypt_state)) = &mut self.udp {
+                    crypt_state.set_decrypt_nonce(&server_nonce);
+                }
+                Ok(None)
+            },
+
+            _ => Err(anyhow::Error::msg(format!("bad crypt setup: {:?}", crypt_setup))),
+        }
+    }
+}
+
 async fn ping(server_stream: &mut Framed<TcpStream, ClientControlCodec>) -> Result<()> {
     // first we measure the current timestamp before sending it
     use std::time::{SystemTime,UNIX_EPOCH};